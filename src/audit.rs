@@ -0,0 +1,222 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+//! An append-only record of every gateway call, for compliance.
+//!
+//! Each entry is one line of JSON holding the hash of the entry before it, so editing,
+//! removing or reordering any line breaks the chain from there on. [`verify`] walks it.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::error::FzError;
+
+/// The previous hash of the first entry in a log
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One gateway call: who made it, what was asked and what came back. Never the token.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuditEntry {
+    /// When the request was sent
+    pub timestamp: DateTime<Utc>,
+    /// The OS user running the tool
+    pub operator: String,
+    pub host: String,
+    /// The gateway username the call was made as
+    pub merchant: String,
+    /// fetch, void, refund, capture or search
+    pub action: String,
+    pub reference: String,
+    pub purchase_id: Option<String>,
+    /// None when no reply arrived
+    pub http_status: Option<u16>,
+    /// The reply body, as JSON when it parses
+    pub response: Option<serde_json::Value>,
+    /// Why no reply arrived
+    pub error: Option<String>,
+    pub prev_hash: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// The hash of the entry with its own hash left out
+    fn digest(&self) -> Result<String, serde_json::Error> {
+        let unhashed = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let line = serde_json::to_string(&unhashed)?;
+        Ok(format!("{:x}", Sha256::digest(line.as_bytes())))
+    }
+}
+
+/// The open log and the hash the next entry chains from
+#[derive(Debug)]
+struct Chain {
+    path: PathBuf,
+    file: File,
+    last_hash: String,
+    /// How long the log was after the last entry this process read or wrote, so an entry
+    /// appended by another process since then is noticed
+    len: u64,
+    /// Set once a write fails, after which no more calls are allowed
+    broken: Option<String>,
+}
+
+impl Chain {
+    /// Append an entry under an exclusive lock on the file, so runs sharing a log
+    /// never both chain from the same entry
+    fn append(&mut self, entry: &mut AuditEntry) -> io::Result<()> {
+        self.file.lock()?;
+        let written = self.append_locked(entry);
+        let unlocked = self.file.unlock();
+        written.and(unlocked)
+    }
+
+    fn append_locked(&mut self, entry: &mut AuditEntry) -> io::Result<()> {
+        if self.file.metadata()?.len() != self.len {
+            self.last_hash = last_hash(File::open(&self.path)?, &self.path).map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        entry.prev_hash = self.last_hash.clone();
+        entry.hash = entry.digest()?;
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        //Write the whole line at once so a crash leaves at most one partial entry
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.len = self.file.metadata()?.len();
+        self.last_hash = entry.hash.clone();
+        Ok(())
+    }
+}
+
+/// An audit log file shared by every request a client makes
+#[derive(Debug)]
+pub struct AuditLog {
+    operator: String,
+    host: String,
+    chain: Mutex<Chain>,
+}
+
+impl AuditLog {
+    /// Open the log for appending, carrying on the chain from its last entry
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, FzError> {
+        let path = path.as_ref();
+        let unreadable = |e: io::Error| FzError::Config(format!("Error opening audit log {}: {}", path.display(), e));
+
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(unreadable)?;
+        //Hold the lock while reading, so another run can't append between the read and the length
+        file.lock().map_err(unreadable)?;
+        let read = File::open(path)
+            .map_err(unreadable)
+            .and_then(|f| last_hash(f, path))
+            .and_then(|hash| Ok((hash, file.metadata().map_err(unreadable)?.len())));
+        file.unlock().map_err(unreadable)?;
+        let (last_hash, len) = read?;
+
+        Ok(Self {
+            operator: operator(),
+            host: gethostname::gethostname().to_string_lossy().into_owned(),
+            chain: Mutex::new(Chain {
+                path: path.to_path_buf(),
+                file,
+                last_hash,
+                len,
+                broken: None,
+            }),
+        })
+    }
+
+    /// Refuse to make a call if an earlier entry could not be written, so nothing goes unrecorded
+    pub(crate) fn check(&self) -> Result<(), FzError> {
+        let chain = self.chain.lock().unwrap_or_else(|e| e.into_inner());
+        match &chain.broken {
+            Some(e) => Err(FzError::Config(format!("Audit log could not be written, so nothing more is sent: {}", e))),
+            None => Ok(()),
+        }
+    }
+
+    /// Chain and append an entry, filling in the operator and hashes. An error means the call
+    /// was made but is missing from the log, and nothing more will be sent.
+    pub(crate) fn record(&self, mut entry: AuditEntry) -> Result<(), FzError> {
+        entry.operator = self.operator.clone();
+        entry.host = self.host.clone();
+
+        let mut chain = self.chain.lock().unwrap_or_else(|e| e.into_inner());
+        if chain.broken.is_none() {
+            if let Err(e) = chain.append(&mut entry) {
+                chain.broken = Some(e.to_string());
+            }
+        }
+        match &chain.broken {
+            Some(e) => Err(FzError::Config(format!(
+                "The {} of {} was sent but could not be written to the audit log: {}",
+                entry.action, entry.reference, e
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The OS user, from the environment the shell sets
+fn operator() -> String {
+    ["USER", "LOGNAME", "USERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|u| !u.is_empty()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// The hash of the last entry in an existing log
+fn last_hash(file: File, path: &Path) -> Result<String, FzError> {
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| FzError::Config(format!("Error reading audit log {}: {}", path.display(), e)))?;
+        if !line.trim().is_empty() {
+            last = Some(line);
+        }
+    }
+    match last {
+        None => Ok(GENESIS.to_string()),
+        Some(line) => match serde_json::from_str::<AuditEntry>(&line) {
+            Ok(entry) => Ok(entry.hash),
+            Err(_) => Err(FzError::Config(format!(
+                "Audit log {} ends in an unreadable entry, so it can't be continued",
+                path.display()
+            ))),
+        },
+    }
+}
+
+/// Check every entry's hash and its link to the one before, returning how many there are
+pub fn verify(path: impl AsRef<Path>) -> Result<usize, FzError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| FzError::Config(format!("Error opening audit log {}: {}", path.display(), e)))?;
+    let broken = |number: usize, why: &str| FzError::Input(format!("Audit log {} is broken at line {}: {}", path.display(), number, why));
+
+    let mut prev_hash = GENESIS.to_string();
+    let mut count = 0;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let number = i + 1;
+        let line = line.map_err(|e| broken(number, &e.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: AuditEntry = serde_json::from_str(&line).map_err(|e| broken(number, &e.to_string()))?;
+        if entry.prev_hash != prev_hash {
+            return Err(broken(number, "it doesn't follow the entry before it"));
+        }
+        if entry.digest().ok().as_ref() != Some(&entry.hash) {
+            return Err(broken(number, "its contents don't match its hash"));
+        }
+        prev_hash = entry.hash;
+        count += 1;
+    }
+    Ok(count)
+}
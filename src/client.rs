@@ -0,0 +1,418 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use chrono::Utc;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::audit::{AuditEntry, AuditLog};
+use crate::error::FzError;
+use crate::ratelimit::RateLimiter;
+use crate::purchase::{Amount, Purchase};
+use crate::response::FetchResponses;
+use crate::retry;
+
+/// Tries per request unless the caller asks for something else
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// How long to wait for each reply unless the caller asks for something else
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    Sandbox,
+    Production,
+    Custom,
+}
+
+impl Environment {
+    /// The gateway base URL for this environment, if it has a fixed one
+    pub fn base_url(self) -> Option<&'static str> {
+        match self {
+            Environment::Sandbox => Some("https://gateway.pmnts-sandbox.io"),
+            Environment::Production => Some("https://gateway.pmnts.io"),
+            Environment::Custom => None,
+        }
+    }
+}
+
+/// The gateway endpoints, with each reference or id appended as one percent-encoded path segment
+#[derive(Debug, Clone)]
+struct Url {
+    base_url: String,
+    /// None when the base URL can't have paths added to it, which every request then reports
+    base: Option<reqwest::Url>,
+}
+
+impl Url {
+    fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            base: reqwest::Url::parse(base_url).ok().filter(|url| !url.cannot_be_a_base()),
+        }
+    }
+
+    /// The base URL followed by `segments`, so a `#`, `?`, `/` or space stays inside its segment
+    fn endpoint(&self, segments: &[&str]) -> Result<reqwest::Url, FzError> {
+        let invalid = || FzError::Config(format!("Invalid base URL: {}", self.base_url));
+        let mut url = self.base.clone().ok_or_else(invalid)?;
+        url.path_segments_mut().map_err(|_| invalid())?.pop_if_empty().extend(segments);
+        Ok(url)
+    }
+
+    fn get_fetch_url(&self, refx: &str) -> Result<reqwest::Url, FzError> {
+        self.endpoint(&["v1.0", "purchases", refx])
+    }
+
+    fn get_void_url(&self, id: &str) -> Result<reqwest::Url, FzError> {
+        let mut url = self.endpoint(&["v1.0", "purchases", "void"])?;
+        url.query_pairs_mut().append_pair("id", id);
+        Ok(url)
+    }
+
+    fn get_refund_url(&self) -> Result<reqwest::Url, FzError> {
+        self.endpoint(&["v1.0", "refunds"])
+    }
+
+    fn get_capture_url(&self, id: &str) -> Result<reqwest::Url, FzError> {
+        self.endpoint(&["v1.0", "purchases", id, "capture"])
+    }
+
+    fn get_release_url(&self, id: &str) -> Result<reqwest::Url, FzError> {
+        self.endpoint(&["v1.0", "purchases", id, "release"])
+    }
+
+    fn get_search_url(&self) -> Result<reqwest::Url, FzError> {
+        self.endpoint(&["v1.0", "purchases"])
+    }
+}
+
+/// The date range and page of purchases to list
+#[derive(Debug, Default, Clone)]
+pub struct SearchQuery {
+    /// The first day to search, e.g. 2022-01-31
+    pub from: String,
+    /// The last day to search, or None for today
+    pub to: Option<String>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+/// What a request is for, so errors and the audit log can name it
+#[derive(Debug, Clone, Copy)]
+struct Call<'a> {
+    action: &'static str,
+    reference: &'a str,
+    purchase_id: Option<&'a str>,
+}
+
+impl<'a> Call<'a> {
+    fn new(action: &'static str, reference: &'a str, purchase_id: Option<&'a str>) -> Self {
+        Self {
+            action,
+            reference,
+            purchase_id,
+        }
+    }
+}
+
+/// A client for one merchant's gateway account, cheap to share between tasks.
+///
+/// Every method retries timeouts, dropped connections, 429s and 5xx replies,
+/// but only when repeating the request can't act on a purchase twice.
+pub struct FatZebraClient {
+    username: String,
+    token: String,
+    url: Url,
+    /// One pooled connection shared by every request
+    client: reqwest::Client,
+    max_attempts: u32,
+    timeout: Duration,
+    /// Shared by every request so the whole run stays under the gateway's quota
+    limiter: RateLimiter,
+    /// Where every call is recorded, if anywhere
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl fmt::Debug for FatZebraClient {
+    //Never print the token
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FatZebraClient")
+            .field("username", &self.username)
+            .field("url", &self.url)
+            .field("max_attempts", &self.max_attempts)
+            .field("timeout", &self.timeout)
+            .field("limiter", &self.limiter)
+            .field("audit", &self.audit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FatZebraClient {
+    pub fn new(username: &str, token: &str, base_url: &str) -> Self {
+        Self {
+            username: username.to_string(),
+            token: token.to_string(),
+            url: Url::new(base_url),
+            client: reqwest::Client::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            timeout: DEFAULT_TIMEOUT,
+            limiter: RateLimiter::new(None),
+            audit: None,
+        }
+    }
+
+    /// How many times to try a request in total (default 3)
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// How long to wait for each reply (default 10 seconds)
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The most requests per second to send, or None for no limit (the default)
+    pub fn with_rate(mut self, rate: Option<f64>) -> Self {
+        self.limiter = RateLimiter::new(rate);
+        self
+    }
+
+    /// Record every call in this audit log (by default nothing is recorded)
+    pub fn with_audit(mut self, audit: Option<AuditLog>) -> Self {
+        self.audit = audit.map(Arc::new);
+        self
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The total time requests have spent held back by the rate limit or Retry-After
+    pub fn throttled(&self) -> Duration {
+        self.limiter.throttled()
+    }
+
+    /// The Basic authorization header value for the merchant credentials
+    fn authorization(&self) -> String {
+        let mut auth_str = String::new();
+        auth_str.push_str(&self.username);
+        auth_str.push(':');
+        auth_str.push_str(&self.token);
+
+        "Basic ".to_owned() + &base64::encode(auth_str)
+    }
+
+    /// Authenticate and send a gateway request, record it, then parse the reply
+    async fn send<T: DeserializeOwned + Default>(
+        &self,
+        request: reqwest::RequestBuilder,
+        call: Call<'_>,
+    ) -> Result<FetchResponses<T>, FzError> {
+        if let Some(audit) = &self.audit {
+            audit.check()?;
+        }
+        self.limiter.acquire().await;
+        let timestamp = Utc::now();
+        let reply = self.exchange(request).await;
+        if let Some(audit) = &self.audit {
+            let (http_status, body) = match &reply {
+                Ok((status, body)) => (Some(*status), Some(body)),
+                Err(_) => (None, None),
+            };
+            audit.record(AuditEntry {
+                timestamp,
+                merchant: self.username.clone(),
+                action: call.action.to_string(),
+                reference: call.reference.to_string(),
+                purchase_id: call.purchase_id.map(str::to_string),
+                http_status,
+                response: body.filter(|b| !b.is_empty()).map(|b| {
+                    serde_json::from_str(b).unwrap_or_else(|_| serde_json::Value::String(b.clone()))
+                }),
+                error: reply.as_ref().err().map(FzError::to_string),
+                ..Default::default()
+            })?;
+        }
+
+        let (status, http_response) = reply?;
+        if status == 401 {
+            return Err(FzError::AuthFailed);
+        }
+        //Throttling and outages are reported by status whatever the body says, so they can be retried
+        if status == 429 || status >= 500 {
+            return Err(FzError::HttpStatus(status));
+        }
+
+        let mut r: FetchResponses<T> = match serde_json::from_str(http_response.as_str()) {
+            Ok(r) => r,
+            Err(_) if status == 404 => return Err(FzError::NotFound(call.reference.to_string())),
+            Err(_) if status >= 400 => return Err(FzError::HttpStatus(status)),
+            Err(source) => return Err(FzError::JsonDecode { status, source }),
+        };
+        r.status = status;
+
+        Ok(r)
+    }
+
+    /// Send a request and read the status and body of the reply
+    async fn exchange(&self, request: reqwest::RequestBuilder) -> Result<(u16, String), FzError> {
+        let response = request
+            .header("Accept", "application/json")
+            .header("Authorization", self.authorization())
+            .timeout(self.timeout)
+            .send()
+            .await?;
+        let status = response.status().as_u16();
+        if status == 429 {
+            if let Some(wait) = retry_after(&response) {
+                self.limiter.pause_for(wait);
+            }
+        }
+        //The body of an error status is only kept for the audit log, so losing it changes nothing
+        let body = match response.text().await {
+            Ok(body) => body,
+            Err(_) if status == 401 || status == 429 || status >= 500 => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok((status, body))
+    }
+
+    /// Retry `send` while `retryable` allows, recording how many attempts it took
+    async fn send_with_retries<T, F>(
+        &self,
+        retryable: fn(&FzError) -> bool,
+        request: F,
+        call: Call<'_>,
+    ) -> Result<FetchResponses<T>, FzError>
+    where
+        T: DeserializeOwned + Default,
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let send = || self.send(request(), call);
+        let (mut r, attempts) = retry::with_retries(self.max_attempts, retryable, send).await?;
+        r.attempts = attempts;
+        Ok(r)
+    }
+
+    /// Look up a purchase by its reference
+    pub async fn fetch_purchase(&self, refx: &str) -> Result<FetchResponses, FzError> {
+        let url = self.url.get_fetch_url(refx)?;
+        let request = || {
+            self.client
+                .get(url.clone())
+                .header("Content-Type", "application/json")
+        };
+        self.send_with_retries(FzError::is_transient, request, Call::new("fetch", refx, None)).await
+    }
+
+    /// Void the purchase with gateway id `id`, retrying only when it is certain an earlier
+    /// attempt didn't land. Check the reply with [`FetchResponses::void_landed`].
+    pub async fn void_purchase(&self, refx: &str, id: &str) -> Result<FetchResponses, FzError> {
+        let mut attempt = 1;
+        let url = self.url.get_void_url(id)?;
+        loop {
+            let request = self
+                .client
+                .post(url.clone())
+                .header("Content-Type", "application/json");
+            let e = match self.send(request, Call::new("void", refx, Some(id))).await {
+                Ok(mut b) => {
+                    b.attempts = attempt;
+                    return Ok(b);
+                }
+                Err(e) => e,
+            };
+            if attempt >= self.max_attempts || !e.is_transient() {
+                return Err(e);
+            }
+            tokio::time::sleep(retry::backoff(attempt)).await;
+
+            //The void may have landed before the reply was lost, so look at the purchase first
+            if !e.was_not_sent() {
+                match self.fetch_purchase(refx).await {
+                    Ok(mut fe) if fe.successful && fe.is_voided() => {
+                        fe.attempts = attempt;
+                        return Ok(fe);
+                    }
+                    Ok(fe) if fe.successful => {}
+                    _ => return Err(e),
+                }
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Refund `amount` of the purchase with gateway id `id`
+    pub async fn refund(&self, refx: &str, id: &str, amount: Amount) -> Result<FetchResponses, FzError> {
+        //The refund reference is derived from the purchase and amount, so a rerun can't refund
+        //the same amount twice but later partial refunds of other amounts still go through
+        let body = json!({
+            "transaction_id": id,
+            "amount": amount,
+            "reference": format!("{}-refund-{}", refx, amount),
+        });
+
+        let url = self.url.get_refund_url()?;
+        let request = || self.client.post(url.clone()).json(&body);
+        self.send_with_retries(FzError::was_not_sent, request, Call::new("refund", refx, Some(id))).await
+    }
+
+    /// Capture `amount` of the authorization with gateway id `id`
+    pub async fn capture(&self, refx: &str, id: &str, amount: Amount) -> Result<FetchResponses, FzError> {
+        let body = json!({ "amount": amount });
+
+        let url = self.url.get_capture_url(id)?;
+        let request = || self.client.post(url.clone()).json(&body);
+        self.send_with_retries(FzError::was_not_sent, request, Call::new("capture", refx, Some(id))).await
+    }
+
+    /// Release the funds held by the uncaptured authorization with gateway id `id`.
+    /// Authorizations can't go through [`FatZebraClient::void_purchase`].
+    pub async fn release_authorization(&self, refx: &str, id: &str) -> Result<FetchResponses, FzError> {
+        let url = self.url.get_release_url(id)?;
+        let request = || {
+            self.client
+                .post(url.clone())
+                .header("Content-Type", "application/json")
+        };
+        self.send_with_retries(FzError::was_not_sent, request, Call::new("release", refx, Some(id))).await
+    }
+
+    /// List the purchases made in a date range
+    pub async fn search_purchases(
+        &self,
+        search: &SearchQuery,
+    ) -> Result<FetchResponses<Vec<Purchase>>, FzError> {
+        let mut query = vec![
+            ("from", search.from.clone()),
+            ("limit", search.limit.to_string()),
+            ("offset", search.offset.to_string()),
+        ];
+        if let Some(to) = &search.to {
+            query.push(("to", to.clone()));
+        }
+
+        let url = self.url.get_search_url()?;
+        let request = || self.client.get(url.clone()).query(&query);
+        let call = Call::new("search", &search.from, None);
+        self.send_with_retries(FzError::is_transient, request, call).await
+    }
+}
+
+/// How long a 429 reply asks us to wait, given in seconds or as an HTTP date
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get("Retry-After")?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let until = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (until.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().ok()
+}
@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use clap::{ArgEnum, Args};
+use fzvoid::{Amount, Purchase};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::sync::Mutex;
+
+use crate::{Mode, Ready};
+
+/// When to ask before changing purchases
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Confirm {
+    /// Show every purchase in one table and ask once
+    Batch,
+    /// Ask about each purchase in turn
+    Each,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct ConfirmArgs {
+    /// Ask before changing purchases (the default against production is batch)
+    #[clap(long, arg_enum)]
+    pub confirm: Option<Confirm>,
+    /// Don't ask, even against production
+    #[clap(short, long)]
+    pub yes: bool,
+}
+
+/// What the user said to the per-item prompt, remembered for the rest of the run
+#[derive(Debug, Default)]
+pub struct Answers {
+    rest: Mutex<Option<bool>>,
+}
+
+/// Whether there is someone at a terminal to answer prompts.
+/// The prompts read the terminal directly, so stdin can still carry the input.
+pub fn interactive() -> bool {
+    io::stderr().is_terminal() && File::open("/dev/tty").is_ok()
+}
+
+fn verb(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Refund => "Refund",
+        Mode::Capture => "Capture",
+        _ => "Void",
+    }
+}
+
+fn customer(p: &Purchase) -> &str {
+    if p.card_holder.is_empty() {
+        &p.card_number
+    } else {
+        &p.card_holder
+    }
+}
+
+fn date(p: &Purchase) -> String {
+    match p.transaction_date {
+        Some(date) => date.format("%Y-%m-%d %H:%M").to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Read one answer from the terminal
+fn ask(question: &str) -> io::Result<String> {
+    eprint!("{}", question);
+    io::stderr().flush()?;
+    let mut line = String::new();
+    BufReader::new(File::open("/dev/tty")?).read_line(&mut line)?;
+    Ok(line.trim().to_lowercase())
+}
+
+/// Print a table of the purchases about to be changed and ask once for all of them
+pub fn batch(mode: Mode, ready: &[(&str, &Ready)]) -> io::Result<bool> {
+    if ready.is_empty() {
+        return Ok(true);
+    }
+
+    let mut totals: BTreeMap<&str, i64> = BTreeMap::new();
+    eprintln!("{:<24} {:>14} {:<24} Date", "Reference", "Amount", "Customer");
+    for (refx, r) in ready {
+        let p = &r.purchase;
+        eprintln!(
+            "{:<24} {:>14} {:<24} {}",
+            refx,
+            format!("{} {}", r.amount, p.currency),
+            customer(p),
+            date(p)
+        );
+        *totals.entry(&p.currency).or_default() += r.amount.cents();
+    }
+    let totals: Vec<String> = totals
+        .iter()
+        .map(|(currency, cents)| format!("{} {}", Amount::from_cents(*cents), currency))
+        .collect();
+
+    let answer = ask(&format!(
+        "{} {} purchases totalling {}? [y/N] ",
+        verb(mode),
+        ready.len(),
+        totals.join(" + ")
+    ))?;
+    Ok(answer == "y" || answer == "yes")
+}
+
+impl Answers {
+    /// Ask about one purchase: yes, no, all of the rest, or quit and skip the rest
+    pub fn each(&self, mode: Mode, refx: &str, ready: &Ready) -> io::Result<bool> {
+        let mut rest = self.rest.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(answer) = *rest {
+            return Ok(answer);
+        }
+
+        let p = &ready.purchase;
+        let question = format!(
+            "{} {} - {} {} - {} - {}? [y/N/a/q] ",
+            verb(mode),
+            refx,
+            ready.amount,
+            p.currency,
+            customer(p),
+            date(p)
+        );
+        match ask(&question)?.as_str() {
+            "y" | "yes" => Ok(true),
+            "a" | "all" => {
+                *rest = Some(true);
+                Ok(true)
+            }
+            "q" | "quit" => {
+                *rest = Some(false);
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+
+use fzvoid::Environment;
+
+/// A named set of credentials and gateway settings from the config file
+#[derive(Deserialize, Default, Debug, Clone)]
+pub struct Profile {
+    pub username: Option<String>,
+    pub token: Option<String>,
+    pub environment: Option<Environment>,
+    pub base_url: Option<String>,
+    pub audit_log: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+struct Config {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// Where a credential came from, for --verbose output
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Flag,
+    Env(&'static str),
+    Profile(String),
+    Prompt,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Flag => write!(f, "the command line"),
+            Source::Env(var) => write!(f, "the {} environment variable", var),
+            Source::Profile(name) => write!(f, "profile {}", name),
+            Source::Prompt => write!(f, "the interactive prompt"),
+        }
+    }
+}
+
+/// The merchant credentials and any gateway settings that came with them
+#[derive(Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub username_source: Source,
+    pub token: String,
+    pub token_source: Source,
+    pub profile: Profile,
+}
+
+/// The config file to read: `--config`, then `FZ_CONFIG`, then the user's config directory
+fn config_path(config: Option<&str>) -> Option<PathBuf> {
+    if let Some(path) = config {
+        return Some(PathBuf::from(path));
+    }
+    if let Ok(path) = env::var("FZ_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let base = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(env::var("HOME").ok()?).join(".config"),
+    };
+    Some(base.join("fzvoid").join("config.toml"))
+}
+
+/// Load the named profile, or the `default` profile when none was asked for
+fn load_profile(config: Option<&str>, profile: Option<&str>) -> Result<Option<(String, Profile)>, Box<dyn Error>> {
+    let path = match config_path(config) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    //A missing config file only matters if the user asked for something from it
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if config.is_none() && profile.is_none() && e.kind() == io::ErrorKind::NotFound => {
+            return Ok(None)
+        }
+        Err(e) => return Err(format!("Error reading config file {}: {}", path.display(), e).into()),
+    };
+    let parsed: Config = toml::from_str(&contents)
+        .map_err(|e| format!("Error parsing config file {}: {}", path.display(), e))?;
+
+    let name = profile.unwrap_or("default");
+    match parsed.profiles.get(name) {
+        Some(p) => Ok(Some((name.to_string(), p.clone()))),
+        None if profile.is_none() => Ok(None),
+        None => Err(format!("Profile {} not found in {}", name, path.display()).into()),
+    }
+}
+
+fn prompt(label: &str, hidden: bool) -> Result<String, Box<dyn Error>> {
+    if hidden {
+        return Ok(rpassword::prompt_password(label)?);
+    }
+    eprint!("{}", label);
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+/// Resolve one credential, first match wins: flag, environment, profile, then prompt
+fn resolve_one(
+    flag: Option<String>,
+    var: &'static str,
+    from_profile: Option<(String, String)>,
+    name: &str,
+    hidden: bool,
+) -> Result<(String, Source), Box<dyn Error>> {
+    if let Some(value) = flag {
+        return Ok((value, Source::Flag));
+    }
+    if let Ok(value) = env::var(var) {
+        if !value.is_empty() {
+            return Ok((value, Source::Env(var)));
+        }
+    }
+    if let Some((name, value)) = from_profile {
+        return Ok((value, Source::Profile(name)));
+    }
+    if io::stdin().is_terminal() {
+        let value = prompt(&format!("Fat Zebra {}: ", name), hidden)?;
+        if !value.is_empty() {
+            return Ok((value, Source::Prompt));
+        }
+    }
+    Err(format!("Missing {}: please specify --{}, {} or a config profile", name, name, var).into())
+}
+
+pub fn resolve(
+    username: Option<String>,
+    token: Option<String>,
+    config: Option<&str>,
+    profile: Option<&str>,
+) -> Result<Credentials, Box<dyn Error>> {
+    let profile = match profile.map(str::to_string).or_else(|| env::var("FZ_PROFILE").ok()) {
+        Some(name) => load_profile(config, Some(&name))?,
+        None => load_profile(config, None)?,
+    };
+    let (name, profile) = profile.unwrap_or_default();
+
+    let from_profile = |value: &Option<String>| value.clone().map(|v| (name.clone(), v));
+    let (username, username_source) =
+        resolve_one(username, "FZ_USERNAME", from_profile(&profile.username), "username", false)?;
+    let (token, token_source) =
+        resolve_one(token, "FZ_TOKEN", from_profile(&profile.token), "token", true)?;
+
+    Ok(Credentials {
+        username,
+        username_source,
+        token,
+        token_source,
+        profile,
+    })
+}
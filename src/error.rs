@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use std::error::Error;
+use std::fmt;
+
+/// Every reference succeeded or was deliberately skipped
+pub const EXIT_OK: u8 = 0;
+/// At least one reference failed
+pub const EXIT_PARTIAL_FAILURE: u8 = 1;
+/// Nothing could be done: bad options, unreadable input or rejected credentials
+pub const EXIT_COULD_NOT_RUN: u8 = 2;
+
+#[derive(Debug)]
+pub enum FzError {
+    /// The request never got a reply: DNS, connection, TLS or timeout
+    Transport(reqwest::Error),
+    /// The gateway replied with an error status and no usable body
+    HttpStatus(u16),
+    /// The gateway replied with something other than the JSON we expected
+    JsonDecode { status: u16, source: serde_json::Error },
+    /// The gateway processed the request and refused it
+    GatewayDeclined(Vec<String>),
+    /// The gateway has no purchase with this reference
+    NotFound(String),
+    /// The gateway returned a purchase with a different reference from the one asked for
+    WrongPurchase { asked: String, returned: String },
+    /// The purchase had already been voided
+    AlreadyVoided,
+    /// The gateway rejected the username or token
+    AuthFailed,
+    /// A row of input could not be used, so nothing was sent for it
+    Input(String),
+    /// The options or environment don't allow the run to start
+    Config(String),
+}
+
+impl FzError {
+    /// A stable name for scripts reading the structured output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FzError::Transport(_) => "transport",
+            FzError::HttpStatus(_) => "http_status",
+            FzError::JsonDecode { .. } => "json_decode",
+            FzError::GatewayDeclined(_) => "gateway_declined",
+            FzError::NotFound(_) => "not_found",
+            FzError::WrongPurchase { .. } => "wrong_purchase",
+            FzError::AlreadyVoided => "already_voided",
+            FzError::AuthFailed => "auth_failed",
+            FzError::Input(_) => "input",
+            FzError::Config(_) => "config",
+        }
+    }
+
+    /// Timeouts, dropped connections, throttling and gateway outages may clear up on their own
+    pub fn is_transient(&self) -> bool {
+        match self {
+            FzError::Transport(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            FzError::HttpStatus(status) => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// Whether the gateway certainly didn't act on the request, so repeating it is harmless
+    pub fn was_not_sent(&self) -> bool {
+        match self {
+            FzError::Transport(e) => e.is_connect(),
+            FzError::HttpStatus(status) => *status == 429,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for FzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FzError::Transport(e) => write!(f, "{}", e),
+            FzError::HttpStatus(status) => write!(f, "Unexpected HTTP status {}", status),
+            FzError::JsonDecode { status, source } => {
+                write!(f, "Could not decode gateway response (HTTP {}): {}", status, source)
+            }
+            FzError::GatewayDeclined(errors) if errors.is_empty() => write!(f, "Unknown gateway error"),
+            FzError::GatewayDeclined(errors) => write!(f, "{}", errors.join("; ")),
+            FzError::NotFound(reference) => write!(f, "Transaction not found: {}", reference),
+            FzError::WrongPurchase { asked, returned } => {
+                write!(f, "Asked for reference {} but the gateway returned {}", asked, returned)
+            }
+            FzError::AlreadyVoided => write!(f, "Transaction already voided"),
+            FzError::AuthFailed => {
+                write!(f, "Authentication failed: check the username, token and environment")
+            }
+            FzError::Input(msg) | FzError::Config(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for FzError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FzError::Transport(e) => Some(e),
+            FzError::JsonDecode { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for FzError {
+    fn from(e: reqwest::Error) -> Self {
+        FzError::Transport(e)
+    }
+}
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use chrono::Utc;
+use clap::Args;
+use fzvoid::{Amount, Purchase};
+
+/// Checks every fetched purchase must pass before anything is done to it
+#[derive(Args, Clone, Debug, Default)]
+pub struct Guards {
+    /// Skip purchases over this amount, e.g. 100.00
+    #[clap(long)]
+    max_amount: Option<Amount>,
+    /// Skip purchases under this amount
+    #[clap(long)]
+    min_amount: Option<Amount>,
+    /// Skip purchases in any other currency, e.g. AUD
+    #[clap(long)]
+    currency: Option<String>,
+    /// Skip purchases older than this, e.g. 90m, 24h or 7d
+    #[clap(long, parse(try_from_str = parse_age))]
+    not_older_than: Option<chrono::Duration>,
+    /// Skip purchases that have settled, or whose settlement date is unknown
+    #[clap(long)]
+    only_unsettled: bool,
+}
+
+impl Guards {
+    /// Whether any guard was asked for, so purchases have to be fetched to check them
+    pub fn is_active(&self) -> bool {
+        self.max_amount.is_some()
+            || self.min_amount.is_some()
+            || self.currency.is_some()
+            || self.not_older_than.is_some()
+            || self.only_unsettled
+    }
+
+    /// Say why a purchase should be skipped, if it fails any guard
+    pub fn check(&self, p: &Purchase) -> Result<(), String> {
+        if let Some(max) = self.max_amount {
+            if p.amount > max {
+                return Err(format!("Amount {} is over --max-amount {}", p.amount, max));
+            }
+        }
+        if let Some(min) = self.min_amount {
+            if p.amount < min {
+                return Err(format!("Amount {} is under --min-amount {}", p.amount, min));
+            }
+        }
+        if let Some(currency) = &self.currency {
+            if !p.currency.eq_ignore_ascii_case(currency) {
+                return Err(format!("Currency {} is not {}", p.currency, currency.to_uppercase()));
+            }
+        }
+        //Anything we can't date is treated as too old, since the point is to be safe
+        if let Some(age) = self.not_older_than {
+            match p.transaction_date {
+                Some(date) if Utc::now().signed_duration_since(date) <= age => {}
+                Some(date) => return Err(format!("Purchase from {} is older than --not-older-than", date)),
+                None => return Err("Purchase date unknown".to_string()),
+            }
+        }
+        if self.only_unsettled {
+            match p.settlement_date {
+                Some(_) if !p.is_settled() => {}
+                Some(date) => return Err(format!("Purchase settled on {}", date)),
+                None => return Err("Settlement date unknown".to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse an age such as "90m", "24h" or "7d"
+fn parse_age(age: &str) -> Result<chrono::Duration, String> {
+    let invalid = || format!("Invalid age {}: use a form like 90m, 24h or 7d", age);
+    let age = age.trim();
+    let unit = age.chars().last().ok_or_else(invalid)?;
+    let count: i64 = age[..age.len() - unit.len_utf8()].parse().map_err(|_| invalid())?;
+    if count <= 0 {
+        return Err(invalid());
+    }
+    let duration = match unit {
+        's' => chrono::Duration::try_seconds(count),
+        'm' => chrono::Duration::try_minutes(count),
+        'h' => chrono::Duration::try_hours(count),
+        'd' => chrono::Duration::try_days(count),
+        _ => None,
+    };
+    duration.ok_or_else(invalid)
+}
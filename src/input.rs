@@ -0,0 +1,464 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use clap::ArgEnum;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use fzvoid::FzError;
+use std::collections::HashMap;
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// How each line of input is split into columns
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum InputFormat {
+    /// A reference per line, with an optional amount after a comma or tab
+    #[default]
+    Lines,
+    /// Comma-separated columns under a header row
+    Csv,
+    /// Tab-separated columns under a header row
+    Tsv,
+}
+
+/// What each input line holds
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Key {
+    /// Tell gateway ids from merchant references by their shape
+    #[default]
+    Auto,
+    /// Every line is a merchant reference
+    Reference,
+    /// Every line is a gateway purchase id
+    Id,
+}
+
+impl Key {
+    pub fn is_id(self, value: &str) -> bool {
+        match self {
+            Key::Auto => looks_like_id(value),
+            Key::Reference => false,
+            Key::Id => true,
+        }
+    }
+}
+
+/// Whether a value has the shape of a gateway transaction id, such as 071-P-ZVWY2XKF
+fn looks_like_id(value: &str) -> bool {
+    let parts: Vec<&str> = value.split('-').collect();
+    match parts[..] {
+        [branch, kind, code] => {
+            branch.len() == 3
+                && branch.bytes().all(|b| b.is_ascii_digit())
+                && (1..=2).contains(&kind.len())
+                && kind.bytes().all(|b| b.is_ascii_uppercase())
+                && code.len() >= 6
+                && code.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        }
+        _ => false,
+    }
+}
+
+/// A column picked by its header or its 1-based position
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    Name(String),
+    Index(usize),
+}
+
+/// Parse a column given as a header name or a 1-based index
+pub fn parse_column(column: &str) -> Result<Column, String> {
+    match column.trim().parse::<usize>() {
+        Ok(0) => Err("Column indexes start at 1".to_string()),
+        Ok(index) => Ok(Column::Index(index - 1)),
+        Err(_) => Ok(Column::Name(column.trim().to_string())),
+    }
+}
+
+/// How to read the input and which columns hold what
+#[derive(Clone, Debug, Default)]
+pub struct Layout {
+    pub format: InputFormat,
+    pub key: Key,
+    pub reference: Option<Column>,
+    pub amount: Option<Column>,
+    pub note: Option<Column>,
+    /// Whether the first line is a header row (CSV and TSV only)
+    pub header: bool,
+}
+
+/// A reference from the command line or input file, with an optional per-row amount
+#[derive(Debug, Default, Clone)]
+pub struct Row {
+    /// The merchant reference, or the gateway purchase id when `id` is set
+    pub reference: String,
+    /// Whether `reference` is a gateway purchase id, so it doesn't need looking up
+    pub id: bool,
+    /// Whether `id` was only guessed from the reference's shape, so it may be a reference after all
+    pub guessed: bool,
+    pub amount: Option<String>,
+    /// A note to show alongside the result
+    pub note: Option<String>,
+    /// The input's other columns by header, carried through to the output
+    pub columns: Vec<(String, String)>,
+    /// Why the line could not be read, in which case nothing is sent for it
+    pub error: Option<String>,
+    /// The line this reference first appeared on, if it is a repeat
+    pub duplicate_of: Option<usize>,
+}
+
+/// Where each field is found in a CSV or TSV row
+#[derive(Debug, Default)]
+struct Mapping {
+    delimiter: u8,
+    reference: usize,
+    amount: Option<usize>,
+    note: Option<usize>,
+    /// The header of every column, or column_1, column_2... as many as the first row has when there is no header row
+    names: Vec<String>,
+}
+
+impl Mapping {
+    fn is_mapped(&self, index: usize) -> bool {
+        [Some(self.reference), self.amount, self.note].contains(&Some(index))
+    }
+
+    fn name(&self, index: usize) -> String {
+        self.names.get(index).cloned().unwrap_or_else(|| format!("column_{}", index + 1))
+    }
+
+    /// The names of the columns that aren't mapped, in order
+    fn columns(&self) -> Vec<String> {
+        (0..self.names.len()).filter(|i| !self.is_mapped(*i)).map(|i| self.name(i)).collect()
+    }
+}
+
+type Reader = BufReader<Box<dyn AsyncRead + Unpin>>;
+
+/// Where the input stream has got to
+struct Lines {
+    /// None once a read has failed
+    reader: Option<Reader>,
+    number: usize,
+    mapping: Option<Mapping>,
+    key: Key,
+    /// The line each reference was first seen on
+    seen: HashMap<String, usize>,
+    /// A record already read to size the columns, handed out before reading on
+    pending: Option<Record>,
+}
+
+/// One record of input: a line, or several when a quoted CSV field runs over line breaks
+#[derive(Debug, Default)]
+struct Record {
+    bytes: Vec<u8>,
+    /// The line the record starts on
+    line: usize,
+    /// The input ended inside a quoted field
+    unterminated: bool,
+}
+
+/// The byte order mark some editors put at the start of a file
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Stream rows from the input file, or from stdin for "-", as each line arrives.
+/// Also gives the names of the input's other columns, which every row carries in that order.
+pub async fn read_rows(filename: &str, layout: &Layout) -> Result<(Vec<String>, LocalBoxStream<'static, Row>), FzError> {
+    let input: Box<dyn AsyncRead + Unpin> = if filename == "-" {
+        Box::new(tokio::io::stdin())
+    } else {
+        match tokio::fs::File::open(filename).await {
+            Ok(file) => Box::new(file),
+            Err(e) => return Err(FzError::Config(format!("Error opening file {}: {}", filename, e))),
+        }
+    };
+    let mut reader: Reader = BufReader::new(input);
+
+    //The header has to be read up front, since a column it lacks means the run can't start
+    let mut number = 0;
+    let mut pending = None;
+    let unreadable = |e: io::Error| FzError::Config(format!("Error reading {}: {}", filename, e));
+    let mapping = match layout.format {
+        InputFormat::Lines if layout.reference.is_some() || layout.amount.is_some() || layout.note.is_some() => {
+            return Err(FzError::Config(
+                "--reference-column, --amount-column and --note-column need --input-format csv or tsv".to_string(),
+            ));
+        }
+        InputFormat::Lines => None,
+        InputFormat::Csv | InputFormat::Tsv => {
+            let delimiter = if layout.format == InputFormat::Csv { b',' } else { b'\t' };
+            let header = if layout.header {
+                let record = read_record(&mut reader, Some(delimiter), &mut number)
+                    .await
+                    .map_err(unreadable)?
+                    .unwrap_or_default();
+                if record.unterminated {
+                    return Err(FzError::Config("The header row has a quoted field that is never closed".to_string()));
+                }
+                let line = String::from_utf8(clean(&record.bytes).to_vec())
+                    .map_err(|_| FzError::Config("The header row is not valid UTF-8".to_string()))?;
+                Some(split(&line, delimiter).iter().map(|n| n.trim().to_string()).collect())
+            } else {
+                None
+            };
+            let mut mapping = mapping(layout, delimiter, header)?;
+            //With no header, the first record with anything in it says how many columns there are
+            if !layout.header {
+                while let Some(record) = read_record(&mut reader, Some(delimiter), &mut number).await.map_err(unreadable)? {
+                    let line = String::from_utf8_lossy(clean(&record.bytes)).trim().to_string();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let width = split(&line, delimiter).len();
+                    mapping.names = (1..=width).map(|i| format!("column_{}", i)).collect();
+                    pending = Some(record);
+                    break;
+                }
+            }
+            Some(mapping)
+        }
+    };
+    let columns = mapping.as_ref().map(Mapping::columns).unwrap_or_default();
+
+    let lines = Lines {
+        reader: Some(reader),
+        number,
+        mapping,
+        key: layout.key,
+        seen: HashMap::new(),
+        pending,
+    };
+    let rows = stream::unfold(lines, |mut lines| async move {
+        let mut reader = lines.reader.take()?;
+        let delimiter = lines.mapping.as_ref().map(|m| m.delimiter);
+        let record = match lines.pending.take() {
+            Some(record) => Ok(Some(record)),
+            None => read_record(&mut reader, delimiter, &mut lines.number).await,
+        };
+        match record {
+            Ok(None) => None,
+            //Nothing is looked up for a record cut short, since its reference may be a fragment
+            Ok(Some(record)) if record.unterminated => {
+                let error = format!("Line {} has a quoted field that is never closed", record.line);
+                let row = unusable_row(clean(&record.bytes), lines.mapping.as_ref(), error);
+                Some((Some(row), lines))
+            }
+            Ok(Some(record)) => {
+                let row = read_row(&record.bytes, record.line, lines.mapping.as_ref()).map(|mut row| {
+                    row.id = lines.key.is_id(&row.reference);
+                    row.guessed = row.id && lines.key == Key::Auto;
+                    dedupe(row, record.line, &mut lines.seen)
+                });
+                lines.reader = Some(reader);
+                Some((row, lines))
+            }
+            //Stop at a read error, after reporting it against the line it happened on
+            Err(e) => {
+                let error = Some(format!("Could not read line {}: {}", lines.number + 1, e));
+                Some((Some(Row { error, ..Default::default() }), lines))
+            }
+        }
+    });
+    //Blank lines, comments and header rows produce no row at all
+    let rows = rows.filter_map(|row| async move { row });
+    Ok((columns, rows.boxed_local()))
+}
+
+/// Read the next record, carrying on over line breaks inside quoted CSV or TSV fields.
+/// Plain lists (no delimiter) are read a line at a time.
+async fn read_record(reader: &mut Reader, delimiter: Option<u8>, number: &mut usize) -> io::Result<Option<Record>> {
+    let mut record = Record {
+        line: *number + 1,
+        ..Default::default()
+    };
+    loop {
+        if reader.read_until(b'\n', &mut record.bytes).await? == 0 {
+            if record.bytes.is_empty() {
+                return Ok(None);
+            }
+            record.unterminated = true;
+            return Ok(Some(record));
+        }
+        *number += 1;
+        match delimiter {
+            Some(delimiter) if in_quotes(&record.bytes, delimiter) => continue,
+            _ => return Ok(Some(record)),
+        }
+    }
+}
+
+/// Whether a CSV record read so far ends inside a quoted field, following the csv crate's rules:
+/// a quote only opens a field at its start, and a doubled quote inside one is a literal quote
+fn in_quotes(record: &[u8], delimiter: u8) -> bool {
+    let record = record.strip_prefix(BOM).unwrap_or(record).trim_ascii_start();
+    let mut quoted = false;
+    let mut field_start = true;
+    let mut bytes = record.iter().peekable();
+    while let Some(&b) = bytes.next() {
+        if quoted {
+            if b == b'"' {
+                if bytes.peek() == Some(&&b'"') {
+                    bytes.next();
+                } else {
+                    quoted = false;
+                }
+            }
+        } else if b == b'"' && field_start {
+            quoted = true;
+            field_start = false;
+        } else {
+            field_start = b == delimiter || b == b'\n';
+        }
+    }
+    quoted
+}
+
+/// Find the columns named in the layout, falling back to headers called reference, amount and note
+fn mapping(layout: &Layout, delimiter: u8, header: Option<Vec<String>>) -> Result<Mapping, FzError> {
+    let names = header.clone().unwrap_or_default();
+    let find = |column: &Column| match column {
+        Column::Index(index) => Ok(*index),
+        Column::Name(name) if header.is_none() => Err(FzError::Config(format!(
+            "Column {} can only be picked by index when there is no header row",
+            name
+        ))),
+        Column::Name(name) => names
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(name))
+            .ok_or_else(|| FzError::Config(format!("No column named {} in the header row", name))),
+    };
+    let named = |name: &str| names.iter().position(|n| n.eq_ignore_ascii_case(name));
+
+    let reference = match &layout.reference {
+        Some(column) => find(column)?,
+        None => named("reference").unwrap_or(0),
+    };
+    let amount = match &layout.amount {
+        Some(column) => Some(find(column)?),
+        None => named("amount"),
+    };
+    let note = match &layout.note {
+        Some(column) => Some(find(column)?),
+        None => named("note"),
+    };
+
+    Ok(Mapping {
+        delimiter,
+        reference,
+        amount,
+        note,
+        names,
+    })
+}
+
+/// Split one line of CSV or TSV, honouring quotes
+fn split(line: &str, delimiter: u8) -> Vec<String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(line.as_bytes());
+    match reader.records().next() {
+        Some(Ok(record)) => record.iter().map(str::to_string).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Strip the line ending, and the byte order mark if there is one
+fn clean(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    line.strip_prefix(BOM).unwrap_or(line)
+}
+
+/// Turn one record of raw input into a row, or a row carrying the reason it can't be used.
+/// Lines with nothing to process give None.
+fn read_row(line: &[u8], number: usize, mapping: Option<&Mapping>) -> Option<Row> {
+    let line = clean(line);
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => line.trim(),
+        Err(_) => return Some(unusable_row(line, mapping, format!("Line {} is not valid UTF-8", number))),
+    };
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let row = match mapping {
+        Some(mapping) => parse_columns(line, mapping),
+        None => parse_row(line),
+    };
+    //A plain list that starts with a header would otherwise look up a purchase called "reference"
+    if mapping.is_none() && number == 1 && row.reference.eq_ignore_ascii_case("reference") {
+        return None;
+    }
+    Some(row)
+}
+
+/// A row for a record that can't be used, split as well as it can be so the output still
+/// shows its reference and columns
+fn unusable_row(line: &[u8], mapping: Option<&Mapping>, error: String) -> Row {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim();
+    let row = match mapping {
+        Some(mapping) => parse_columns(line, mapping),
+        None => parse_row(line),
+    };
+    Row {
+        error: Some(error),
+        ..row
+    }
+}
+
+/// Mark a reference already seen on an earlier line, so it is only processed once
+fn dedupe(mut row: Row, number: usize, seen: &mut HashMap<String, usize>) -> Row {
+    if row.error.is_some() || row.reference.is_empty() {
+        return row;
+    }
+    match seen.get(&row.reference) {
+        Some(first) => row.duplicate_of = Some(*first),
+        None => {
+            seen.insert(row.reference.clone(), number);
+        }
+    }
+    row
+}
+
+/// Split an input line into its reference and an optional amount in the second column
+fn parse_row(line: &str) -> Row {
+    let mut columns = line.split([',', '\t']);
+    Row {
+        reference: columns.next().unwrap_or_default().trim().to_string(),
+        amount: columns
+            .next()
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(str::to_string),
+        ..Default::default()
+    }
+}
+
+/// Pick the mapped fields out of a CSV or TSV line and keep the rest by header
+fn parse_columns(line: &str, mapping: &Mapping) -> Row {
+    let fields = split(line, mapping.delimiter);
+    let field = |index: Option<usize>| {
+        index
+            .and_then(|i| fields.get(i))
+            .map(|f| f.trim())
+            .filter(|f| !f.is_empty())
+            .map(str::to_string)
+    };
+
+    //Rows shorter than the header still report every column, so CSV output stays rectangular
+    let width = mapping.names.len().max(fields.len());
+    let columns = (0..width)
+        .filter(|i| !mapping.is_mapped(*i))
+        .map(|i| (mapping.name(i), fields.get(i).cloned().unwrap_or_default()))
+        .collect();
+
+    Row {
+        reference: field(Some(mapping.reference)).unwrap_or_default(),
+        amount: field(mapping.amount),
+        note: field(mapping.note),
+        columns,
+        ..Default::default()
+    }
+}
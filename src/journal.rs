@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::Mutex;
+use std::{
+    fs::{File, OpenOptions},
+    io::{prelude::*, BufReader},
+    path::Path,
+};
+
+use crate::{Mode, Processed};
+
+/// Results that mean there is nothing left to do for a reference
+const COMPLETE: [&str; 4] = ["voided", "released", "refunded", "captured"];
+
+/// One line of the journal, written as soon as a reference has been processed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalEntry {
+    /// What the run was doing, so a resume only skips what it would have done itself
+    pub mode: String,
+    pub reference: String,
+    pub purchase_id: Option<String>,
+    pub result: String,
+    pub timestamp: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+/// An append-only JSON Lines record of every reference a run has processed
+pub struct Journal {
+    file: Mutex<File>,
+    mode: Mode,
+}
+
+impl Journal {
+    pub fn open(path: impl AsRef<Path>, mode: Mode) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Error opening journal {}: {}", path.display(), e))?;
+        Ok(Self {
+            file: Mutex::new(file),
+            mode,
+        })
+    }
+
+    pub fn record(&self, processed: &Processed) -> Result<(), Box<dyn Error>> {
+        let entry = JournalEntry {
+            mode: self.mode.name().to_string(),
+            reference: processed.reference.clone(),
+            purchase_id: processed.purchase_id.clone(),
+            result: processed.outcome.name().to_string(),
+            timestamp: Utc::now(),
+            error: processed.outcome.error(),
+        };
+
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        //Write the whole line at once so an interrupted run leaves at most one partial entry
+        let mut file = self.file.lock().map_err(|_| "Journal lock poisoned")?;
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// The references an earlier run in the same mode has already voided or refunded, according to its journal
+pub fn completed(path: impl AsRef<Path>, mode: Mode) -> Result<HashSet<String>, Box<dyn Error>> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("Error opening journal {}: {}", path.display(), e))?;
+    let buf = BufReader::new(file);
+
+    let mut done = HashSet::new();
+    for line in buf.lines() {
+        //A run killed mid-write can leave a truncated last line, so skip anything unparseable
+        if let Ok(entry) = serde_json::from_str::<JournalEntry>(&line?) {
+            if entry.mode == mode.name() && COMPLETE.contains(&entry.result.as_str()) {
+                done.insert(entry.reference);
+            }
+        }
+    }
+    Ok(done)
+}
@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+//! A client for the Fat Zebra gateway, used by the `fzvoid` command line tool.
+//!
+//! ```no_run
+//! use fzvoid::{Environment, FatZebraClient};
+//!
+//! # async fn example() -> Result<(), fzvoid::FzError> {
+//! let base_url = Environment::Sandbox.base_url().unwrap();
+//! let client = FatZebraClient::new("merchant", "token", base_url);
+//!
+//! let purchase = client.fetch_purchase("order-1234").await?;
+//! if let Some(p) = purchase.response() {
+//!     let reply = client.void_purchase(&p.reference, &p.id).await?;
+//!     if !reply.void_landed(&p.reference) {
+//!         return Err(reply.error(&p.reference));
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod audit;
+mod client;
+pub mod error;
+mod purchase;
+mod ratelimit;
+mod response;
+mod retry;
+
+pub use client::{Environment, FatZebraClient, SearchQuery};
+pub use error::FzError;
+pub use purchase::{Amount, Purchase};
+pub use response::FetchResponses;
@@ -2,336 +2,1129 @@
 //
 // Copyright (c) 2022 Robert Mascaro
 
+mod confirm;
+mod credentials;
+mod guards;
+mod input;
+mod journal;
 mod macros;
+mod output;
 
-use clap::Parser;
-use futures::executor::block_on;
+use clap::{ArgEnum, Args, Parser, Subcommand};
+use confirm::{Confirm, ConfirmArgs};
+use futures::stream::{self, StreamExt};
+use fzvoid::error::{EXIT_COULD_NOT_RUN, EXIT_OK, EXIT_PARTIAL_FAILURE};
+use fzvoid::audit::{self, AuditLog};
+use fzvoid::{Amount, Environment, FatZebraClient, FetchResponses, FzError, Purchase, SearchQuery};
+use guards::Guards;
+use input::{Column, InputFormat, Key, Layout, Row};
+use journal::Journal;
+use output::{OutputFormat, Reporter};
+use std::cell::Cell;
 use std::error::Error;
-use std::time::Duration;
+use std::fmt;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
 
-use serde::Deserialize;
-use serde::Deserializer;
-use serde_json;
-use std::{
-    fs::File,
-    io::{prelude::*, BufReader},
-    path::Path,
-};
-
-#[derive(Debug)]
-struct StrError<'a>(&'a str);
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 #[clap(name = "fzvoid")]
 #[clap(author = "Robert Mascaro")]
 #[clap(version = "1.0")]
-#[clap(about = "Void a Fat Zebra transaction", long_about = None)]
+#[clap(about = "Void, refund, fetch, capture and search Fat Zebra transactions", long_about = None)]
 #[derive(Clone)]
 struct Cli {
     /// The Fat Zebra merchant username
-    #[clap(short, long)]
-    username: String,
-    /// The API Token
-    #[clap(short, long)]
-    token: String,
+    #[clap(short, long, global = true)]
+    username: Option<String>,
+    /// The API Token (prefer FZ_TOKEN or a profile, which stay out of shell history)
+    #[clap(short, long, global = true)]
+    token: Option<String>,
+    /// The gateway to send requests to
+    #[clap(short, long, arg_enum, global = true)]
+    environment: Option<EnvironmentArg>,
+    /// Override the gateway base URL (required with --environment custom)
+    #[clap(long, global = true)]
+    base_url: Option<String>,
+    /// Read credentials and gateway settings from this profile in the config file
+    #[clap(short, long, global = true)]
+    profile: Option<String>,
+    /// The config file (defaults to $FZ_CONFIG or ~/.config/fzvoid/config.toml)
+    #[clap(long, global = true)]
+    config: Option<String>,
+    /// How to print one record per reference
+    #[clap(short, long, arg_enum, default_value = "text", global = true)]
+    output: OutputFormat,
+    /// Print where the credentials and gateway came from
+    #[clap(short, long, global = true)]
+    verbose: bool,
+    /// How many times to try a request that fails with a timeout, dropped connection, 429 or 5xx
+    #[clap(long, default_value_t = 3, global = true)]
+    max_attempts: u32,
+    /// Seconds to wait for each gateway response
+    #[clap(long, default_value_t = 10, global = true)]
+    timeout: u64,
+    /// The most requests to send, e.g. 10/s or 300/m (defaults to no limit)
+    #[clap(long, parse(try_from_str = parse_rate), global = true)]
+    rate: Option<f64>,
+    /// Append every gateway call to this hash-chained audit log (defaults to $FZ_AUDIT_LOG or the profile's)
+    #[clap(long, global = true)]
+    audit_log: Option<String>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/// The library's [`Environment`] as a command line choice
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum EnvironmentArg {
+    Sandbox,
+    Production,
+    Custom,
+}
+
+impl From<EnvironmentArg> for Environment {
+    fn from(environment: EnvironmentArg) -> Self {
+        match environment {
+            EnvironmentArg::Sandbox => Environment::Sandbox,
+            EnvironmentArg::Production => Environment::Production,
+            EnvironmentArg::Custom => Environment::Custom,
+        }
+    }
+}
+
+/// Parse a rate such as "10/s", "300/m" or plain "5" into requests per second
+pub fn parse_rate(rate: &str) -> Result<f64, String> {
+    let (count, per) = match rate.split_once('/') {
+        Some((count, per)) => (count, per),
+        None => (rate, "s"),
+    };
+    let seconds = match per {
+        "s" | "sec" => 1.0,
+        "m" | "min" => 60.0,
+        _ => return Err(format!("Invalid rate {}: use a form like 10/s or 300/m", rate)),
+    };
+    match count.trim().parse::<f64>() {
+        Ok(count) if count > 0.0 && count.is_finite() => Ok(count / seconds),
+        _ => Err(format!("Invalid rate {}: use a form like 10/s or 300/m", rate)),
+    }
+}
+
+/// A refund or capture amount, which has to be more than nothing
+fn parse_amount(amount: &str) -> Result<Amount, FzError> {
+    let parsed: Amount = amount.parse()?;
+    if parsed.cents() == 0 {
+        return Err(FzError::Input(format!("Invalid amount: {} is nothing to refund or capture", amount)));
+    }
+    Ok(parsed)
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Void purchases
+    Void(VoidArgs),
+    /// Refund purchases in full or for a given amount
+    Refund(AmountArgs),
+    /// Fetch and print purchases without changing them
+    Fetch(InputArgs),
+    /// Capture authorized purchases in full or for a given amount
+    Capture(AmountArgs),
+    /// List purchases made in a date range
+    Search(SearchArgs),
+    /// Show whether each purchase exists and is voided, refunded or settled, without changing anything
+    Status(InputArgs),
+    /// Check that an audit log's hash chain is unbroken
+    VerifyAudit(VerifyAuditArgs),
+}
+
+#[derive(Args, Clone)]
+struct VerifyAuditArgs {
+    /// The audit log to check
+    file: String,
+}
+
+/// Where the references come from and how to work through them
+#[derive(Args, Clone)]
+struct InputArgs {
     /// The purchase reference
     #[clap(short, long)]
     reference: Option<String>,
-    /// The upload filename
+    /// The upload filename, or - to read references from stdin as they arrive
     #[clap(short, long)]
     filename: Option<String>,
+    /// Read references from stdin as they arrive, the same as --filename -
+    #[clap(long, conflicts_with = "filename")]
+    stdin: bool,
+    /// A gateway purchase id, e.g. 071-P-ZVWY2XKF, used as is without looking it up by reference
+    #[clap(long, conflicts_with_all = &["reference", "filename", "stdin"])]
+    id: Option<String>,
+    /// A file of gateway purchase ids, or - for stdin, the same as --filename with --key id
+    #[clap(long, conflicts_with_all = &["reference", "filename", "stdin", "id"])]
+    ids_file: Option<String>,
+    /// Whether input lines are merchant references, gateway purchase ids, or auto to tell by their shape
+    #[clap(long, arg_enum, default_value = "auto")]
+    key: Key,
+    /// How to split each line of input into columns
+    #[clap(long, arg_enum, default_value = "lines")]
+    input_format: InputFormat,
+    /// The CSV/TSV column holding the reference, by header or 1-based index (default: reference, else the first)
+    #[clap(long, parse(try_from_str = input::parse_column))]
+    reference_column: Option<Column>,
+    /// The CSV/TSV column holding each row's amount (default: amount, if there is one)
+    #[clap(long, parse(try_from_str = input::parse_column))]
+    amount_column: Option<Column>,
+    /// The CSV/TSV column holding a note to show with each result (default: note, if there is one)
+    #[clap(long, parse(try_from_str = input::parse_column))]
+    note_column: Option<Column>,
+    /// The CSV/TSV input has no header row, so columns are picked by index
+    #[clap(long)]
+    no_header: bool,
+    /// The maximum number of references processed at once
+    #[clap(short, long, default_value_t = 1)]
+    concurrency: usize,
+    /// Append each reference's outcome to this journal file as it is processed
+    #[clap(short, long)]
+    journal: Option<String>,
+    /// Skip references already done in this journal and append new outcomes to it
+    #[clap(long, conflicts_with = "journal")]
+    resume: Option<String>,
+}
+
+impl InputArgs {
+    /// --id and --ids-file mean every input is a gateway id, whatever --key says
+    fn key(&self) -> Key {
+        if self.id.is_some() || self.ids_file.is_some() {
+            Key::Id
+        } else {
+            self.key
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+struct VoidArgs {
+    #[clap(flatten)]
+    input: InputArgs,
+    /// Fetch and report each purchase without voiding it
+    #[clap(long)]
+    dry_run: bool,
+    /// Refund instead when the gateway says a purchase can no longer be voided
+    #[clap(long)]
+    void_or_refund: bool,
+    /// Fetch each purchase again after voiding it, to confirm it now shows as voided
+    #[clap(long)]
+    verify: bool,
+    #[clap(flatten)]
+    guards: Guards,
+    #[clap(flatten)]
+    confirm: ConfirmArgs,
+}
+
+#[derive(Args, Clone)]
+struct AmountArgs {
+    #[clap(flatten)]
+    input: InputArgs,
+    /// Fetch and report each purchase without changing it
+    #[clap(long)]
+    dry_run: bool,
+    /// Amount, e.g. 12.50 (defaults to the full purchase amount; a second column in the file overrides it)
+    #[clap(short, long)]
+    amount: Option<String>,
+    #[clap(flatten)]
+    guards: Guards,
+    #[clap(flatten)]
+    confirm: ConfirmArgs,
+}
+
+#[derive(Args, Clone)]
+struct SearchArgs {
+    /// The first day to search, e.g. 2022-01-31
+    #[clap(long)]
+    from: String,
+    /// The last day to search (defaults to today)
+    #[clap(long)]
+    to: Option<String>,
+    /// The maximum number of purchases to list
+    #[clap(long, default_value_t = 100)]
+    limit: usize,
+    /// The number of purchases to skip before listing
+    #[clap(long, default_value_t = 0)]
+    offset: usize,
+}
+
+/// What to do with each purchase once it has been fetched
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Mode {
+    #[default]
+    Void,
+    Refund,
+    VoidOrRefund,
+    Fetch,
+    Capture,
+    Status,
+}
+
+impl Mode {
+    /// The name recorded in the journal
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Void => "void",
+            Mode::Refund => "refund",
+            Mode::VoidOrRefund => "void_or_refund",
+            Mode::Fetch => "fetch",
+            Mode::Capture => "capture",
+            Mode::Status => "status",
+        }
+    }
+
+    /// Whether this mode only looks purchases up
+    fn is_lookup(self) -> bool {
+        matches!(self, Mode::Fetch | Mode::Status)
+    }
+
+    /// Whether this mode moves money and so reads an amount from the input
+    fn uses_amount(self) -> bool {
+        matches!(self, Mode::Refund | Mode::VoidOrRefund | Mode::Capture)
+    }
 }
 
 #[derive(Debug)]
 struct Params {
-    username: String,
-    token: String,
+    client: FatZebraClient,
     reference: String,
     filename: String,
+    dry_run: bool,
+    /// Re-fetch each voided purchase to confirm the void landed
+    verify: bool,
+    mode: Mode,
+    /// The refund or capture amount from the command line
+    amount: Option<Amount>,
+    /// Checked against each purchase before it is changed
+    guards: Guards,
+    /// Whether to ask before changing each purchase or batch
+    confirm: Option<Confirm>,
 }
 
-struct Url {
-    sandbox_fetch_url: String,
-    production_fetch_url: String,
-    sandbox_void_url: String,
-    production_void_url: String,
+/// The result of running a single reference through the fetch-then-void pipeline
+#[derive(Debug)]
+enum Outcome {
+    /// Nothing has happened yet. Left in place by mistake, it is reported as a failure.
+    Pending,
+    Voided,
+    /// An uncaptured authorization's hold on the card was released
+    Released,
+    Refunded(Amount),
+    Captured(Amount),
+    Fetched(Purchase),
+    WouldVoid(Purchase),
+    WouldRelease(Purchase),
+    WouldRefund(Purchase, Amount),
+    WouldCapture(Purchase, Amount),
+    Failed(FzError),
+    Skipped(String),
+    /// The gateway accepted the void, but the purchase fetched afterwards doesn't show it
+    Unverified(String),
+    /// The purchase as it stands, or None if the gateway has no such purchase
+    Status(Option<Purchase>),
 }
 
-#[derive(Deserialize, Default, Debug)]
-#[serde(default)]
-struct FetchResponses {
-    successful: bool,
-    #[serde(deserialize_with = "deserialize_optional_field")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    response: Option<Option<FetchResponse>>,
-    #[serde(deserialize_with = "deserialize_optional_field")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    errors: Option<Option<FetchErrors>>,
-}
+impl Outcome {
+    fn is_auth_failed(&self) -> bool {
+        matches!(self, Outcome::Failed(FzError::AuthFailed))
+    }
+
+    /// The short name recorded in the journal
+    fn name(&self) -> &'static str {
+        match self {
+            Outcome::Pending => "not_processed",
+            Outcome::Voided => "voided",
+            Outcome::Released => "released",
+            Outcome::Refunded(_) => "refunded",
+            Outcome::Captured(_) => "captured",
+            Outcome::Fetched(_) => "fetched",
+            Outcome::WouldVoid(_) => "would_void",
+            Outcome::WouldRelease(_) => "would_release",
+            Outcome::WouldRefund(..) => "would_refund",
+            Outcome::WouldCapture(..) => "would_capture",
+            Outcome::Failed(_) => "failed",
+            Outcome::Skipped(_) => "skipped",
+            Outcome::Unverified(_) => "unverified",
+            Outcome::Status(Some(_)) => "found",
+            Outcome::Status(None) => "not_found",
+        }
+    }
+
+    /// Why a reference failed or was skipped
+    fn error(&self) -> Option<String> {
+        match self {
+            Outcome::Failed(e) => Some(e.to_string()),
+            Outcome::Skipped(e) | Outcome::Unverified(e) => Some(e.clone()),
+            Outcome::Pending => Some("Not processed".to_string()),
+            _ => None,
+        }
+    }
 
-#[derive(Deserialize, Default, Debug)]
-struct FetchResponse {
-    //successful: bool,
-    id: String,
-    //reference: String,
+    /// The class of failure, for scripts that act on it
+    fn error_kind(&self) -> Option<&'static str> {
+        match self {
+            Outcome::Failed(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Deserialize, Default, Debug)]
-#[serde(transparent)]
-struct FetchErrors {
+/// A reference together with the purchase id it resolved to and what happened to it
+#[derive(Debug)]
+struct Processed {
+    reference: String,
+    purchase_id: Option<String>,
+    /// The last gateway call made for this reference
+    action: &'static str,
+    http_status: Option<u16>,
+    successful: Option<bool>,
     errors: Vec<String>,
+    elapsed: Duration,
+    outcome: Outcome,
+    /// The note and other columns from the input row, so results can be matched back to it
+    note: Option<String>,
+    columns: Vec<(String, String)>,
 }
 
-fn deserialize_optional_field<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
-where
-    D: Deserializer<'de>,
-    T: Deserialize<'de>,
-{
-    //Ok(Some(Option::deserialize(deserializer)?))
-    let _result: Option<T> = match Option::deserialize(deserializer) {
-        Ok(_result) => match _result {
-            Some(r) => return Ok(r),
-            _ => return Ok(None),
-        },
-        Err(_e) => {
-            return Ok(None);
+impl Processed {
+    fn new(reference: &str, purchase_id: Option<&str>, outcome: Outcome) -> Self {
+        Self {
+            reference: reference.to_string(),
+            purchase_id: purchase_id.map(str::to_string),
+            action: "none",
+            http_status: None,
+            successful: None,
+            errors: Vec::new(),
+            elapsed: Duration::default(),
+            outcome,
+            note: None,
+            columns: Vec::new(),
         }
-    };
-}
+    }
 
-impl FetchResponses {
-    async fn fetch_purchase(_args: &Params, refx: &String) -> Result<FetchResponses, Box<dyn Error>> {
-        let mut auth_str = String::new();
-        auth_str.push_str(&_args.username);
-        auth_str.push(':');
-        auth_str.push_str(&_args.token);
-
-        let auth = base64::encode(auth_str);
-
-        let client = reqwest::Client::new();
-        let http_response = match client
-            .get(Url::new().get_fetch_url(&_args.username) + &refx)
-            .header("Accept", "application/json")
-            .header("Content-Type", "application/json")
-            .header("Authorization", "Basic ".to_owned() + &auth)
-            .timeout(Duration::from_secs(10))
-            .send()
-            .await?
-            .text()
-            .await {
-                Ok(hr) => hr,
-                Err(_) => Err("Error getting transaction http response")?
-            };
+    fn from_row(row: &Row, outcome: Outcome) -> Self {
+        Self {
+            note: row.note.clone(),
+            columns: row.columns.clone(),
+            ..Self::new(&row.reference, None, outcome)
+        }
+    }
 
-        let r: FetchResponses = match serde_json::from_str(http_response.as_str()) {
-            Ok(r) => r,
-            Err(_) => {
-                return return_error("Error fetching transaction: ", &refx);
-            }
-        };
+    /// Forget the previous call's reply before making `action`
+    fn attempt(&mut self, action: &'static str) {
+        self.action = action;
+        self.http_status = None;
+        self.successful = None;
+        self.errors.clear();
+    }
 
-        Ok(r)
-    }
-
-    async fn void_transaction(
-        _args: &Params,
-        refx: &String,
-        id: String,
-    ) -> Result<FetchResponses, Box<dyn Error>> {
-        let mut auth_str = String::new();
-        auth_str.push_str(&_args.username);
-        auth_str.push(':');
-        auth_str.push_str(&_args.token);
-
-        let auth = base64::encode(auth_str);
-
-        let client = reqwest::Client::new();
-        let http_response = client
-            .post(Url::new().get_void_url(&_args.username) + &id)
-            .header("Accept", "application/json")
-            .header("Content-Type", "application/json")
-            .header("Authorization", "Basic ".to_owned() + &auth)
-            .timeout(Duration::from_secs(10))
-            .send()
-            .await?
-            .text()
-            .await?;
-
-        match serde_json::from_str(http_response.as_str()) {
-            Ok(r) => {
-                let b: FetchResponses = r;
-                //p!(b);
-                if b.successful {
-                    println!("{} - Voided",&refx);
-                    return Ok(b);
-                } else {
-                    println!("{} - Voiding failed - {:?}",&refx,b.errors.unwrap().unwrap().errors.first().unwrap());
-                    return return_error("Error voiding transaction: ", &refx);
+    /// Remember what the gateway said in reply to `action`
+    fn observe<T>(&mut self, action: &'static str, fe: &FetchResponses<T>) {
+        self.action = action;
+        self.http_status = Some(fe.status);
+        self.successful = Some(fe.successful);
+        self.errors = fe.errors().to_vec();
+    }
+}
+
+/// Running totals printed at the end of a batch
+#[derive(Debug, Default)]
+struct Summary {
+    voided: usize,
+    released: usize,
+    refunded: usize,
+    captured: usize,
+    fetched: usize,
+    would_void: usize,
+    would_release: usize,
+    would_refund: usize,
+    would_capture: usize,
+    failed: usize,
+    auth_failed: usize,
+    skipped: usize,
+    unverified: usize,
+    found: usize,
+    not_found: usize,
+    /// Time requests spent held back by --rate or Retry-After
+    throttled: Duration,
+}
+
+impl Summary {
+    fn record(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Pending => self.failed += 1,
+            Outcome::Voided => self.voided += 1,
+            Outcome::Released => self.released += 1,
+            Outcome::Refunded(_) => self.refunded += 1,
+            Outcome::Captured(_) => self.captured += 1,
+            Outcome::Fetched(_) => self.fetched += 1,
+            Outcome::WouldVoid(_) => self.would_void += 1,
+            Outcome::WouldRelease(_) => self.would_release += 1,
+            Outcome::WouldRefund(..) => self.would_refund += 1,
+            Outcome::WouldCapture(..) => self.would_capture += 1,
+            Outcome::Failed(e) => {
+                self.failed += 1;
+                if let FzError::AuthFailed = e {
+                    self.auth_failed += 1;
                 }
             }
-            Err(_r) => {
-                return return_error("Error voiding transaction: ", &refx);     
-            }
-        };
+            Outcome::Skipped(_) => self.skipped += 1,
+            Outcome::Unverified(_) => self.unverified += 1,
+            Outcome::Status(Some(_)) => self.found += 1,
+            Outcome::Status(None) => self.not_found += 1,
+        }
     }
 }
 
-impl Default for Url {
-    fn default() -> Self {
-        Url {
-            sandbox_fetch_url: "https://gateway.pmnts-sandbox.io/v1.0/purchases/".to_string(),
-            production_fetch_url: "https://gateway.pmnts.io/v1.0/purchases/".to_string(),
-            sandbox_void_url: "https://gateway.pmnts-sandbox.io/v1.0/purchases/void?id="
-                .to_string(),
-            production_void_url: "https://gateway.pmnts.io/v1.0/purchases/void?id=".to_string(),
+impl Summary {
+    /// Rejected credentials stop the run, so whatever else was done it didn't finish
+    fn exit_code(&self) -> ExitCode {
+        if self.failed == 0 && self.unverified == 0 {
+            ExitCode::from(EXIT_OK)
+        } else if self.auth_failed > 0 {
+            ExitCode::from(EXIT_COULD_NOT_RUN)
+        } else {
+            ExitCode::from(EXIT_PARTIAL_FAILURE)
         }
     }
 }
 
-impl Url {
-    fn new() -> Self {
-        return Self {
-            ..Default::default()
-        };
+impl fmt::Display for Summary {
+    /// Lists only the outcomes that happened, then failures and skips
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = [
+            ("Voided", self.voided),
+            ("Released", self.released),
+            ("Refunded", self.refunded),
+            ("Captured", self.captured),
+            ("Fetched", self.fetched),
+            ("Would void", self.would_void),
+            ("Would release", self.would_release),
+            ("Would refund", self.would_refund),
+            ("Would capture", self.would_capture),
+            ("Unverified", self.unverified),
+            ("Found", self.found),
+            ("Not found", self.not_found),
+        ];
+        for (label, count) in counts.iter().filter(|(_, count)| *count > 0) {
+            write!(f, "{}: {}, ", label, count)?;
+        }
+        write!(f, "Failed: {}, Skipped: {}", self.failed, self.skipped)?;
+        if !self.throttled.is_zero() {
+            write!(f, ", Throttled: {:.1}s", self.throttled.as_secs_f64())?;
+        }
+        Ok(())
     }
+}
 
-    fn get_fetch_url(self, merchant_id: &String) -> String {
-        match merchant_id.as_str() {
-            "SC-scnet" => self.sandbox_fetch_url,
-            "TEST" => self.sandbox_fetch_url,
-            _ => self.production_fetch_url,
+impl Params {
+    fn new(client: FatZebraClient) -> Self {
+        Self {
+            client,
+            reference: String::new(),
+            filename: String::new(),
+            dry_run: false,
+            verify: false,
+            mode: Mode::default(),
+            amount: None,
+            guards: Guards::default(),
+            confirm: None,
         }
     }
 
-    fn get_void_url(self, merchant_id: &String) -> String {
-        match merchant_id.as_str() {
-            "SC-scnet" => self.sandbox_void_url,
-            "TEST" => self.sandbox_void_url,
-            _ => self.production_void_url,
-        }
+    /// Whether a purchase known by its gateway id still has to be fetched before it is changed,
+    /// because something needs its details or amount. A capture or refund always does, to check
+    /// what it holds or has left.
+    fn needs_purchase(&self, amount: Option<Amount>) -> bool {
+        self.mode.is_lookup()
+            || self.mode == Mode::Capture
+            || self.mode == Mode::Refund
+            || self.dry_run
+            || self.confirm.is_some()
+            || self.guards.is_active()
+            || (self.mode != Mode::Void && amount.is_none())
     }
 }
 
-impl Params {
-    fn new() -> Self {
-        return Self {
+/// A purchase that has passed every check, waiting to be changed
+#[derive(Debug)]
+struct Ready {
+    purchase: Purchase,
+    amount: Amount,
+    /// False for a gateway id sent on without being looked up, so only its id is known
+    fetched: bool,
+    /// The row again as a merchant reference, when its id was only guessed from its shape
+    guessed: Option<Row>,
+}
+
+/// Fetch and check a reference, returning it ready to change unless that is already the end of it
+async fn prepare(_params: &Params, row: &Row) -> (Processed, Option<Ready>) {
+    let started = Instant::now();
+    let mut processed = Processed::from_row(row, Outcome::Pending);
+
+    let ready = match fetch_and_check(_params, row, &mut processed).await {
+        Ok(ready) => Some(ready),
+        Err(outcome) => {
+            processed.outcome = outcome;
+            None
+        }
+    };
+    processed.elapsed = started.elapsed();
+    (processed, ready)
+}
+
+/// Void, refund or capture a prepared purchase
+async fn change(_params: &Params, processed: &mut Processed, ready: Ready) {
+    let started = Instant::now();
+    processed.outcome = void_or_refund(_params, processed, ready).await;
+    processed.elapsed += started.elapsed();
+}
+
+/// Returns the outcome early when a reference fails, is skipped or needs nothing more doing
+async fn fetch_and_check(_params: &Params, row: &Row, processed: &mut Processed) -> Result<Ready, Outcome> {
+    if let Some(e) = &row.error {
+        return Err(Outcome::Failed(FzError::Input(e.clone())));
+    }
+    if let Some(first) = row.duplicate_of {
+        return Err(Outcome::Skipped(format!("Duplicate of line {}", first)));
+    }
+    let refx = row.reference.as_str();
+    if refx.is_empty() {
+        return Err(Outcome::Skipped("Empty reference".to_string()));
+    }
+
+    //A per-row amount overrides the command line, which defaults to a full refund
+    let amount = match row.amount.as_deref().filter(|_| _params.mode.uses_amount()).map(parse_amount) {
+        Some(Ok(amount)) => Some(amount),
+        Some(Err(e)) => return Err(Outcome::Failed(e)),
+        None => _params.amount,
+    };
+
+    //A gateway id can go straight to the change, saving a request per purchase
+    if row.id && !_params.needs_purchase(amount) {
+        processed.purchase_id = Some(refx.to_string());
+        let purchase = Purchase {
+            id: refx.to_string(),
             ..Default::default()
         };
+        let guessed = row.guessed.then(|| Row {
+            id: false,
+            guessed: false,
+            ..row.clone()
+        });
+        return Ok(Ready {
+            purchase,
+            amount: amount.unwrap_or_default(),
+            fetched: false,
+            guessed,
+        });
+    }
+
+    //A missing purchase is the answer to a status lookup, not a failure
+    let failed = |e: FzError| match e {
+        FzError::NotFound(_) if _params.mode == Mode::Status => Outcome::Status(None),
+        e => Outcome::Failed(e),
+    };
+
+    processed.attempt("fetch");
+    let fe = match _params.client.fetch_purchase(refx).await {
+        Ok(fe) => fe,
+        Err(e) => return Err(failed(e)),
+    };
+    processed.observe("fetch", &fe);
+
+    if !fe.successful {
+        return Err(failed(fe.error(refx)));
     }
+
+    //Never fall through to changing a purchase without an id
+    let f = match fe.into_response() {
+        Some(r) if !r.id.is_empty() => r,
+        _ => return Err(failed(FzError::NotFound(refx.to_string()))),
+    };
+    //Whatever a reference turned into on the way, never act on somebody else's purchase
+    if !row.id && f.reference != refx {
+        let e = FzError::WrongPurchase {
+            asked: refx.to_string(),
+            returned: f.reference.clone(),
+        };
+        return Err(Outcome::Failed(e));
+    }
+    processed.purchase_id = Some(f.id.clone());
+    let full = if _params.mode == Mode::Refund { f.refundable() } else { f.amount };
+    let amount = amount.unwrap_or(full);
+
+    match _params.mode {
+        Mode::Fetch => return Err(Outcome::Fetched(f)),
+        Mode::Status => return Err(Outcome::Status(Some(f))),
+        _ => {}
+    }
+
+    //Only an authorization still holding funds can be captured, and never for more than it holds
+    if _params.mode == Mode::Capture {
+        if !f.is_authorization() {
+            return Err(Outcome::Skipped(format!("Nothing to capture: the purchase is {}", f.state())));
+        }
+        if amount > f.amount {
+            let e = format!("Capture amount {} is over the authorized {}", amount, f.amount);
+            return Err(Outcome::Failed(FzError::Input(e)));
+        }
+    }
+
+    //Earlier partial refunds count against what is left to refund
+    if _params.mode == Mode::Refund && amount > f.refundable() {
+        let e = format!("Refund amount {} is over the {} left to refund", amount, f.refundable());
+        return Err(Outcome::Failed(FzError::Input(e)));
+    }
+
+    if let Err(reason) = _params.guards.check(&f) {
+        return Err(Outcome::Skipped(reason));
+    }
+
+    if _params.dry_run {
+        return Err(match _params.mode {
+            Mode::Refund => Outcome::WouldRefund(f, amount),
+            Mode::Capture => Outcome::WouldCapture(f, amount),
+            _ if f.is_authorization() => Outcome::WouldRelease(f),
+            _ => Outcome::WouldVoid(f),
+        });
+    }
+
+    Ok(Ready {
+        purchase: f,
+        amount,
+        fetched: true,
+        guessed: None,
+    })
 }
 
-impl Default for Params {
-    fn default() -> Self {
-        Params {
-            username: String::new(),
-            token: String::new(),
-            reference: String::new(),
-            filename: String::new(),
+async fn void_or_refund(_params: &Params, processed: &mut Processed, ready: Ready) -> Outcome {
+    let Ready {
+        purchase: f,
+        amount,
+        fetched,
+        guessed,
+    } = ready;
+    let refx = &processed.reference.clone();
+
+    if _params.mode == Mode::Capture {
+        processed.attempt("capture");
+        return match _params.client.capture(refx, &f.id, amount).await {
+            Ok(b) => {
+                processed.observe("capture", &b);
+                if b.successful {
+                    Outcome::Captured(amount)
+                } else {
+                    Outcome::Failed(b.error(refx))
+                }
+            }
+            Err(e) => Outcome::Failed(e),
+        };
+    }
+
+    //Authorizations hold funds rather than take them, so there is nothing to refund either
+    if _params.mode != Mode::Refund && f.is_authorization() {
+        return release(_params, processed, &f.id).await;
+    }
+
+    if _params.mode != Mode::Refund {
+        processed.attempt("void");
+        let voided = _params.client.void_purchase(refx, &f.id).await;
+        if let Ok(b) = &voided {
+            processed.observe("void", b);
+        }
+        //No purchase with a guessed id may just mean a merchant reference that looks like one
+        let missing = match &voided {
+            Ok(b) => b.status == 404,
+            Err(e) => matches!(e, FzError::NotFound(_)),
+        };
+        if let Some(row) = guessed.filter(|_| missing) {
+            return match fetch_and_check(_params, &row, processed).await {
+                Ok(ready) => Box::pin(void_or_refund(_params, processed, ready)).await,
+                Err(outcome) => outcome,
+            };
+        }
+        match voided {
+            Ok(b) => {
+                if b.void_landed(refx) {
+                    return if _params.verify {
+                        verify_void(_params, processed).await
+                    } else {
+                        Outcome::Voided
+                    };
+                }
+                //An id voided without a lookup may turn out to be an authorization
+                if !fetched {
+                    if let Some(outcome) = release_if_authorization(_params, processed, &f.id).await {
+                        return outcome;
+                    }
+                }
+                if _params.mode == Mode::Void || !b.void_window_closed() {
+                    return Outcome::Failed(b.error(refx));
+                }
+            }
+            Err(e) => return Outcome::Failed(e),
         }
     }
+
+    processed.attempt("refund");
+    match _params.client.refund(refx, &f.id, amount).await {
+        Ok(b) => {
+            processed.observe("refund", &b);
+            if b.successful {
+                Outcome::Refunded(amount)
+            } else {
+                Outcome::Failed(b.error(refx))
+            }
+        }
+        Err(e) => Outcome::Failed(e),
+    }
 }
 
-fn return_error<T>(msg: &str, reference: &String) -> Result<T, Box<dyn Error>> 
-{
-    let mut err_str = String::new();
-    err_str.push_str(msg);
-    err_str.push_str(&reference);
-    return Err(err_str.into())
+/// Release an uncaptured authorization
+async fn release(_params: &Params, processed: &mut Processed, id: &str) -> Outcome {
+    let refx = &processed.reference.clone();
+    processed.attempt("release");
+    match _params.client.release_authorization(refx, id).await {
+        Ok(b) => {
+            processed.observe("release", &b);
+            if b.successful {
+                Outcome::Released
+            } else {
+                Outcome::Failed(b.error(refx))
+            }
+        }
+        Err(e) => Outcome::Failed(e),
+    }
 }
 
-fn read_file(filename: impl AsRef<Path>) -> Vec<String> {
-    match File::open(filename).map_err(|_| "Please specify a valid file name") {
-        Ok(file) => {
-            let buf = BufReader::new(file);
-            return buf.lines()
-            .map(|l| l.expect("Could not parse line"))
-            .collect();
+/// Look up a purchase whose void was refused and release it if it is an authorization
+async fn release_if_authorization(_params: &Params, processed: &mut Processed, id: &str) -> Option<Outcome> {
+    match _params.client.fetch_purchase(id).await {
+        Ok(fe) if fe.successful => match fe.into_response() {
+            Some(p) if p.is_authorization() => Some(release(_params, processed, id).await),
+            _ => None,
         },
-        Err(_) => return vec![]
-    };
+        _ => None,
+    }
 }
 
-fn fetch_n_void(_params: &Params,reference: &Option<&String>) -> Result<(), Box<dyn Error>> {
+/// Fetch a purchase the gateway says was voided and check that it now shows as voided
+async fn verify_void(_params: &Params, processed: &mut Processed) -> Outcome {
+    let refx = &processed.reference.clone();
+    processed.attempt("verify");
+    match _params.client.fetch_purchase(refx).await {
+        Ok(fe) => {
+            processed.observe("verify", &fe);
+            if !fe.successful {
+                Outcome::Unverified(format!("Could not fetch the purchase to check the void: {}", fe.error(refx)))
+            } else if fe.is_voided() {
+                Outcome::Voided
+            } else {
+                Outcome::Unverified("The void was accepted but the purchase is not voided".to_string())
+            }
+        }
+        Err(e) => Outcome::Unverified(format!("Could not fetch the purchase to check the void: {}", e)),
+    }
+}
 
-    let mut refx = &_params.reference;
+/// List the purchases in a date range, one record each
+async fn search(_params: &Params, search: &SearchArgs, output: OutputFormat) -> Result<Summary, Box<dyn Error>> {
+    let started = Instant::now();
+    let query = SearchQuery {
+        from: search.from.clone(),
+        to: search.to.clone(),
+        limit: search.limit,
+        offset: search.offset,
+    };
+    let fe = _params.client.search_purchases(&query).await?;
+    if !fe.successful {
+        return Err(fe.error(&search.from).into());
+    }
 
-    if let Some(v) = reference {
-        refx = v;
+    let mut reporter = Reporter::new(output, _params.mode);
+    let mut summary = Summary::default();
+    for f in fe.response().cloned().unwrap_or_default() {
+        let (reference, id) = (f.reference.clone(), f.id.clone());
+        let mut processed = Processed::new(&reference, Some(&id), Outcome::Fetched(f));
+        processed.observe("search", &fe);
+        processed.elapsed = started.elapsed();
+        reporter.write(&processed)?;
+        summary.record(&processed.outcome);
     }
+    reporter.finish()?;
+    summary.throttled = _params.client.throttled();
+    Ok(summary)
+}
 
-    let future_purchase = FetchResponses::fetch_purchase(&_params, &refx);
+/// Run every reference from the command line or input file through the pipeline
+async fn run(_params: &Params, input: &InputArgs, output: OutputFormat) -> Result<Summary, Box<dyn Error>> {
+    let concurrency = input.concurrency.max(1);
 
-    //Not sure why this is needed works anyway - so comment out for now
-    //let handle = tokio::runtime::Handle::current();
-    //handle.enter();
-    if let Ok(fetch_response) = block_on(future_purchase) {
-        let fe = fetch_response;
-        if fe.successful {
-            let f = match fe.response.flatten() {
-                Some(r) => r,
-                _ => FetchResponse {id: 0.to_string()},
-            };
+    let (columns, void_trxs) = if _params.filename.is_empty() {
+        let key = input.key();
+        let id = key.is_id(&_params.reference);
+        let row = Row {
+            reference: _params.reference.clone(),
+            id,
+            guessed: id && key == Key::Auto,
+            ..Default::default()
+        };
+        (Vec::new(), stream::iter(vec![row]).boxed_local())
+    } else {
+        let layout = Layout {
+            format: input.input_format,
+            key: input.key(),
+            reference: input.reference_column.clone(),
+            amount: input.amount_column.clone(),
+            note: input.note_column.clone(),
+            header: !input.no_header,
+        };
+        input::read_rows(&_params.filename, &layout).await?
+    };
+
+    //When resuming, the same journal tells us what to skip and records what we do now
+    let completed = match &input.resume {
+        Some(path) => journal::completed(path, _params.mode)?,
+        None => Default::default(),
+    };
+    let journal = match input.resume.as_ref().or(input.journal.as_ref()) {
+        Some(path) => Some(Journal::open(path, _params.mode)?),
+        None => None,
+    };
 
-            let future_void = FetchResponses::void_transaction(&_params, &refx, f.id);
+    //Both stages draw on the same permits, so no more than `concurrency` requests are ever in flight
+    let permits = &Semaphore::new(concurrency);
+    //Once the gateway rejects the credentials nothing more is sent; rows not yet started are left Pending
+    let rejected = &Cell::new(false);
 
-            if let Ok(_r) = block_on(future_void) {
-                return Ok(());
-            } else {
-                return_error("Error voiding transaction: ", refx)
+    //Fetch and check up to `concurrency` references at once, keeping them in input order
+    let (completed, journal) = (&completed, &journal);
+    let prepared = void_trxs
+        .map(|row| async move {
+            if completed.contains(&row.reference) {
+                let skipped = Outcome::Skipped("Already done in journal".to_string());
+                return (Processed::from_row(&row, skipped), None);
             }
-        } else {
-            println!("{} - Voiding failed - {:?}",&refx,fe.errors.unwrap().unwrap().errors.first().unwrap());
-            return_error("Could not fetch transaction: ", refx)
-        }
+            let _permit = permits.acquire().await;
+            if rejected.get() {
+                return (Processed::from_row(&row, Outcome::Pending), None);
+            }
+            let prepared = prepare(_params, &row).await;
+            rejected.set(rejected.get() || prepared.0.outcome.is_auth_failed());
+            prepared
+        })
+        .buffered(concurrency);
+
+    //A batch is only confirmed once every purchase in it has been fetched and shown
+    let prepared = if _params.confirm == Some(Confirm::Batch) {
+        let all: Vec<(Processed, Option<Ready>)> = prepared.collect().await;
+        let ready: Vec<(&str, &Ready)> = all
+            .iter()
+            .filter_map(|(processed, ready)| Some((processed.reference.as_str(), ready.as_ref()?)))
+            .collect();
+        let approved = confirm::batch(_params.mode, &ready)?;
+        let declined = move |(mut processed, ready): (Processed, Option<Ready>)| {
+            if ready.is_some() && !approved {
+                processed.outcome = Outcome::Skipped("Not confirmed".to_string());
+                return (processed, None);
+            }
+            (processed, ready)
+        };
+        stream::iter(all.into_iter().map(declined)).boxed_local()
     } else {
-        return_error("Error fetching transaction: ", refx)
+        prepared.boxed_local()
+    };
+
+    //Prompts for each purchase have to come one at a time
+    let concurrency = if _params.confirm == Some(Confirm::Each) { 1 } else { concurrency };
+    let answers = &confirm::Answers::default();
+    let mut outcomes = prepared
+        .map(|(mut processed, ready)| async move {
+            if rejected.get() && matches!(processed.outcome, Outcome::Pending) {
+                return None;
+            }
+            if let Some(ready) = ready {
+                let approved = match _params.confirm {
+                    Some(Confirm::Each) => answers.each(_params.mode, &processed.reference, &ready),
+                    _ => Ok(true),
+                };
+                match approved {
+                    Ok(true) => {
+                        let _permit = permits.acquire().await;
+                        if rejected.get() {
+                            return None;
+                        }
+                        change(_params, &mut processed, ready).await;
+                        rejected.set(rejected.get() || processed.outcome.is_auth_failed());
+                    }
+                    Ok(false) => processed.outcome = Outcome::Skipped("Not confirmed".to_string()),
+                    Err(e) => processed.outcome = Outcome::Skipped(format!("Could not confirm: {}", e)),
+                }
+            }
+
+            if let Some(journal) = journal.as_ref().filter(|_| !completed.contains(&processed.reference)) {
+                if let Err(e) = journal.record(&processed) {
+                    eprintln!("{} - Could not write journal - {}", processed.reference, e);
+                }
+            }
+            Some(processed)
+        })
+        .buffered(concurrency);
+
+    let mut reporter = Reporter::new(output, _params.mode).with_columns(columns);
+    let mut summary = Summary::default();
+    //Rows come back in order, so the first one left unsent means nothing after it was changed either
+    while let Some(Some(processed)) = outcomes.next().await {
+        reporter.write(&processed)?;
+        summary.record(&processed.outcome);
     }
+    reporter.finish()?;
+    summary.throttled = _params.client.throttled();
 
+    if rejected.get() {
+        eprintln!("Credentials rejected by the gateway, stopped before the remaining references");
+    }
+    if !_params.filename.is_empty() {
+        eprintln!("{}", summary);
+    }
+    Ok(summary)
 }
 
-
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> ExitCode {
+    //Anything that stops the run from starting is a different failure from references failing
+    match try_main().await {
+        Ok(summary) => summary.exit_code(),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(EXIT_COULD_NOT_RUN)
+        }
+    }
+}
+
+async fn try_main() -> Result<Summary, Box<dyn Error>> {
     //Parse the commandline
     let _args = Cli::parse();
 
+    //Checking an audit log needs no credentials or gateway
+    if let Command::VerifyAudit(args) = &_args.command {
+        let count = audit::verify(&args.file)?;
+        eprintln!("Audit log {} is intact: {} entries", args.file, count);
+        return Ok(Summary::default());
+    }
+
+    let creds = credentials::resolve(
+        _args.username,
+        _args.token,
+        _args.config.as_deref(),
+        _args.profile.as_deref(),
+    )?;
+    if _args.verbose {
+        eprintln!("Using username {} from {}", creds.username, creds.username_source);
+        eprintln!("Using token from {}", creds.token_source);
+    }
+
+    //Resolve the gateway from the explicit environment, never from the username
+    let environment = match _args.environment.map(Environment::from).or(creds.profile.environment) {
+        Some(environment) => environment,
+        None => {
+            return Err(FzError::Config("Missing environment: please specify --environment sandbox|production|custom".to_string()).into());
+        }
+    };
+    let base_url = match (_args.base_url.or(creds.profile.base_url).as_deref(), environment.base_url()) {
+        (Some(base_url), _) => base_url.to_string(),
+        (None, Some(base_url)) => base_url.to_string(),
+        (None, None) => {
+            return Err(FzError::Config("Missing base URL: --environment custom requires --base-url".to_string()).into());
+        }
+    };
+    let audit_log = match _args.audit_log.or_else(|| std::env::var("FZ_AUDIT_LOG").ok().filter(|p| !p.is_empty())).or(creds.profile.audit_log) {
+        Some(path) => {
+            if _args.verbose {
+                eprintln!("Recording every gateway call in {}", path);
+            }
+            Some(AuditLog::open(&path)?)
+        }
+        None => None,
+    };
+    let client = FatZebraClient::new(&creds.username, &creds.token, &base_url)
+        .with_max_attempts(_args.max_attempts)
+        .with_timeout(Duration::from_secs(_args.timeout))
+        .with_rate(_args.rate)
+        .with_audit(audit_log);
+
     //Populate cli optionals
-    let mut _params = Params::new();
+    let mut _params = Params::new(client);
+    if _args.verbose {
+        eprintln!("Using {:?} gateway at {}", environment, base_url);
+    }
 
-    match (_args.filename, _args.reference) {
-        (Some(filename), None) => {
-            _params.username = _args.username;
-            _params.token = _args.token;
+    let (input, amount, confirm) = match _args.command {
+        Command::Search(args) => return search(&_params, &args, _args.output).await,
+        Command::VerifyAudit(_) => unreachable!("handled before the gateway is set up"),
+        Command::Void(args) => {
+            _params.mode = if args.void_or_refund { Mode::VoidOrRefund } else { Mode::Void };
+            _params.dry_run = args.dry_run;
+            _params.verify = args.verify;
+            _params.guards = args.guards;
+            (args.input, None, args.confirm)
+        }
+        Command::Refund(args) => {
+            _params.mode = Mode::Refund;
+            _params.dry_run = args.dry_run;
+            _params.guards = args.guards;
+            (args.input, args.amount, args.confirm)
+        }
+        Command::Capture(args) => {
+            _params.mode = Mode::Capture;
+            _params.dry_run = args.dry_run;
+            _params.guards = args.guards;
+            (args.input, args.amount, args.confirm)
+        }
+        Command::Fetch(input) => {
+            _params.mode = Mode::Fetch;
+            (input, None, ConfirmArgs::default())
+        }
+        Command::Status(input) => {
+            _params.mode = Mode::Status;
+            (input, None, ConfirmArgs::default())
+        }
+    };
+    if let Some(amount) = amount {
+        _params.amount = Some(parse_amount(&amount)?);
+    }
+
+    //Changes to production purchases need someone to approve them, or an explicit --yes
+    _params.confirm = match confirm.confirm {
+        _ if confirm.yes || _params.dry_run || _params.mode.is_lookup() => None,
+        Some(confirm) => Some(confirm),
+        None if environment == Environment::Production => Some(Confirm::Batch),
+        None => None,
+    };
+    if _params.confirm.is_some() && !confirm::interactive() {
+        return Err(FzError::Config("Refusing to run without confirmation: there is no terminal to confirm on, so add --yes".to_string()).into());
+    }
+
+    let filename = if input.stdin { Some("-".to_string()) } else { input.filename.clone().or_else(|| input.ids_file.clone()) };
+    match (&filename, input.reference.as_ref().or(input.id.as_ref())) {
+        (Some(filename), _) => {
             _params.filename = filename.to_string();
             _params.reference = String::new();
         }
         (None, Some(reference)) => {
-            _params.username = _args.username;
-            _params.token = _args.token;
             _params.filename = String::new();
             _params.reference = reference.to_string();
         }
-        (Some(filename), Some(_)) => {
-            _params.username = _args.username;
-            _params.token = _args.token;
-            _params.filename =filename.to_string();
-            _params.reference =  String::new();
+        _ => {
+            return Err(FzError::Config("Nothing to do: please specify a reference, an id or a filename".to_string()).into());
         }
-        _ => (),
     }
 
-    if _params.filename.len() == 0 {
-        fetch_n_void(&_params,&None)
-        
-    } else {
-        let void_trxs = read_file(&_params.filename);
-        if void_trxs.is_empty() { 
-            return return_error("Error opening file: ", &"please check file and path".to_string()); 
-        }
-        for line in void_trxs {
-            let _ = fetch_n_void(&_params,&Some(&line));
-        }
-        Ok(())
-    }
+    run(&_params, &input, _args.output).await
 }
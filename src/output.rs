@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use clap::ArgEnum;
+use serde::{Serialize, Serializer};
+use std::error::Error;
+use std::io::{self, Stdout, Write};
+
+use fzvoid::{Amount, Purchase};
+
+use crate::{Mode, Outcome, Processed};
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Jsonl,
+    Csv,
+}
+
+/// One machine-readable record per reference
+#[derive(Serialize)]
+struct Record<'a> {
+    reference: &'a str,
+    purchase_id: Option<&'a str>,
+    action: &'a str,
+    result: &'a str,
+    http_status: Option<u16>,
+    successful: Option<bool>,
+    errors: &'a [String],
+    error: Option<String>,
+    error_kind: Option<&'a str>,
+    elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<&'a str>,
+    /// The input row's other columns, by header
+    #[serde(serialize_with = "as_map", skip_serializing_if = "<[_]>::is_empty")]
+    columns: &'a [(String, String)],
+    /// Where the purchase stands, for status lookups
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    status: Option<Status<'a>>,
+}
+
+/// What a status lookup found, left empty when the lookup failed
+#[derive(Serialize, Default)]
+struct Status<'a> {
+    exists: Option<bool>,
+    state: Option<&'a str>,
+    voided: Option<bool>,
+    refunded: Option<bool>,
+    settled: Option<bool>,
+    amount: Option<Amount>,
+    currency: Option<&'a str>,
+}
+
+impl<'a> Status<'a> {
+    fn new(outcome: &'a Outcome) -> Option<Self> {
+        match outcome {
+            Outcome::Status(Some(p)) => Some(Self {
+                exists: Some(true),
+                state: Some(p.state()),
+                voided: Some(p.voided),
+                refunded: Some(p.is_refunded()),
+                settled: Some(p.is_settled()),
+                amount: Some(p.amount),
+                currency: Some(&p.currency),
+            }),
+            Outcome::Status(None) => Some(Self {
+                exists: Some(false),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The same record flattened for CSV, which has no room for a list
+#[derive(Serialize)]
+struct CsvRecord<'a> {
+    reference: &'a str,
+    purchase_id: Option<&'a str>,
+    action: &'a str,
+    result: &'a str,
+    http_status: Option<u16>,
+    successful: Option<bool>,
+    errors: String,
+    error: Option<String>,
+    error_kind: Option<&'a str>,
+    elapsed_ms: u64,
+    note: Option<&'a str>,
+}
+
+/// The CSV header, which is written by hand so the input's columns can follow it
+const CSV_HEADER: [&str; 11] = [
+    "reference",
+    "purchase_id",
+    "action",
+    "result",
+    "http_status",
+    "successful",
+    "errors",
+    "error",
+    "error_kind",
+    "elapsed_ms",
+    "note",
+];
+
+/// The columns a status lookup adds to the CSV header
+const STATUS_HEADER: [&str; 7] = ["exists", "state", "voided", "refunded", "settled", "amount", "currency"];
+
+fn as_map<S: Serializer>(columns: &&[(String, String)], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(columns.iter().map(|(name, value)| (name, value)))
+}
+
+impl<'a> Record<'a> {
+    fn new(processed: &'a Processed) -> Self {
+        Self {
+            reference: &processed.reference,
+            purchase_id: processed.purchase_id.as_deref(),
+            action: processed.action,
+            result: processed.outcome.name(),
+            http_status: processed.http_status,
+            successful: processed.successful,
+            errors: &processed.errors,
+            error: processed.outcome.error(),
+            error_kind: processed.outcome.error_kind(),
+            elapsed_ms: processed.elapsed.as_millis() as u64,
+            note: processed.note.as_deref(),
+            columns: &processed.columns,
+            status: Status::new(&processed.outcome),
+        }
+    }
+}
+
+impl<'a> From<Record<'a>> for CsvRecord<'a> {
+    fn from(r: Record<'a>) -> Self {
+        Self {
+            reference: r.reference,
+            purchase_id: r.purchase_id,
+            action: r.action,
+            result: r.result,
+            http_status: r.http_status,
+            successful: r.successful,
+            errors: r.errors.join("; "),
+            error: r.error,
+            error_kind: r.error_kind,
+            elapsed_ms: r.elapsed_ms,
+            note: r.note,
+        }
+    }
+}
+
+/// Writes each processed reference to stdout in the chosen format
+pub struct Reporter {
+    format: OutputFormat,
+    mode: Mode,
+    written: usize,
+    csv: Option<csv::Writer<Stdout>>,
+    /// The input's other columns, named in the CSV header
+    columns: Vec<String>,
+}
+
+impl Reporter {
+    pub fn new(format: OutputFormat, mode: Mode) -> Self {
+        Self {
+            format,
+            mode,
+            written: 0,
+            csv: match format {
+                OutputFormat::Csv => Some(
+                    csv::WriterBuilder::new()
+                        .has_headers(false)
+                        .flexible(true)
+                        .from_writer(io::stdout()),
+                ),
+                _ => None,
+            },
+            columns: Vec::new(),
+        }
+    }
+
+    /// Name the input's other columns in the CSV header, whichever row comes first
+    pub fn with_columns(mut self, columns: Vec<String>) -> Self {
+        self.columns = columns;
+        self
+    }
+
+    pub fn write(&mut self, processed: &Processed) -> Result<(), Box<dyn Error>> {
+        let record = Record::new(processed);
+        match self.format {
+            OutputFormat::Text if self.mode == Mode::Status => {
+                if self.written == 0 {
+                    println!(
+                        "{:<24} {:<6} {:<18} {:<6} {:<8} {:<7} Amount",
+                        "Reference", "Exists", "State", "Voided", "Refunded", "Settled"
+                    );
+                }
+                report_status(processed);
+            }
+            OutputFormat::Text => report(processed, self.mode),
+            OutputFormat::Jsonl => println!("{}", serde_json::to_string(&record)?),
+            OutputFormat::Json => {
+                //Stream the array so long runs don't have to be held in memory
+                let sep = if self.written == 0 { "[" } else { "," };
+                print!("{}\n  {}", sep, serde_json::to_string(&record)?);
+                io::stdout().flush()?;
+            }
+            OutputFormat::Csv => {
+                if let Some(csv) = self.csv.as_mut() {
+                    let columns = record.columns;
+                    let lookup = self.mode == Mode::Status;
+                    if self.written == 0 {
+                        let status = STATUS_HEADER.into_iter().filter(|_| lookup);
+                        let names = self.columns.iter().map(String::as_str);
+                        csv.write_record(CSV_HEADER.into_iter().chain(status).chain(names))?;
+                    }
+                    let mut values: Vec<&str> = columns.iter().map(|(_, value)| value.as_str()).collect();
+                    //A line that couldn't be read at all has no columns, but still fills the row
+                    if values.len() < self.columns.len() {
+                        values.resize(self.columns.len(), "");
+                    }
+                    if lookup {
+                        let status = Status::new(&processed.outcome).unwrap_or_default();
+                        csv.serialize((CsvRecord::from(record), status, values))?;
+                    } else {
+                        csv.serialize((CsvRecord::from(record), values))?;
+                    }
+                    csv.flush()?;
+                }
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.format == OutputFormat::Json {
+            if self.written == 0 {
+                println!("[]");
+            } else {
+                println!("\n]");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The reference, with its note if the input had one
+fn label(processed: &Processed) -> String {
+    match &processed.note {
+        Some(note) => format!("{} ({})", processed.reference, note),
+        None => processed.reference.clone(),
+    }
+}
+
+fn report(processed: &Processed, mode: Mode) {
+    let refx = label(processed);
+    let failed = match mode {
+        Mode::Refund => "Refund failed",
+        Mode::Capture => "Capture failed",
+        Mode::Fetch => "Fetch failed",
+        _ => "Voiding failed",
+    };
+    match &processed.outcome {
+        Outcome::Pending => println!("{} - Not processed", refx),
+        Outcome::Voided => println!("{} - Voided", refx),
+        Outcome::Released => println!("{} - Released", refx),
+        Outcome::Refunded(amount) => println!("{} - Refunded {}", refx, amount),
+        Outcome::Captured(amount) => println!("{} - Captured {}", refx, amount),
+        Outcome::Fetched(f) => println!("{} - {}", refx, describe(f)),
+        Outcome::WouldVoid(f) => println!("{} - Would void {}", refx, describe(f)),
+        Outcome::WouldRelease(f) => println!("{} - Would release {}", refx, describe(f)),
+        Outcome::WouldRefund(f, amount) => {
+            println!("{} - Would refund {} of {}", refx, amount, describe(f))
+        }
+        Outcome::WouldCapture(f, amount) => {
+            println!("{} - Would capture {} of {}", refx, amount, describe(f))
+        }
+        Outcome::Failed(e) => println!("{} - {} - {}", refx, failed, e),
+        Outcome::Skipped(e) => println!("{} - Skipped - {}", refx, e),
+        Outcome::Unverified(e) => println!("{} - Unverified - {}", refx, e),
+        Outcome::Status(_) => report_status(processed),
+    }
+}
+
+/// One row of the status table
+fn report_status(processed: &Processed) {
+    let refx = label(processed);
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    match &processed.outcome {
+        Outcome::Status(Some(p)) => println!(
+            "{:<24} {:<6} {:<18} {:<6} {:<8} {:<7} {} {}",
+            refx,
+            "yes",
+            p.state(),
+            yes_no(p.voided),
+            yes_no(p.is_refunded()),
+            yes_no(p.is_settled()),
+            p.amount,
+            p.currency
+        ),
+        Outcome::Status(None) => println!("{:<24} no", refx),
+        outcome => println!("{:<24} {:<6} {}", refx, "?", outcome.error().unwrap_or_default()),
+    }
+}
+
+/// The purchase details worth checking before acting on it
+fn describe(f: &Purchase) -> String {
+    let date = match f.transaction_date {
+        Some(date) => date.format("%Y-%m-%d %H:%M").to_string(),
+        None => "unknown date".to_string(),
+    };
+    let card = if f.card_holder.is_empty() {
+        f.card_number.clone()
+    } else {
+        format!("{} {}", f.card_holder, f.card_number)
+    };
+    format!(
+        "{} - {} {} - {} - {} - {} - {}",
+        f.id, f.amount, f.currency, card, date, f.state(), f.message
+    )
+}
@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::FzError;
+
+/// An amount of money in cents, as the gateway counts it
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub fn from_cents(cents: i64) -> Self {
+        Amount(cents)
+    }
+
+    pub fn cents(self) -> i64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Amount {
+    /// Format cents as a decimal amount
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        write!(f, "{}{}.{:02}", sign, self.0.abs() / 100, self.0.abs() % 100)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = FzError;
+
+    /// Convert a decimal amount such as "12.5" or "12.50" into cents
+    fn from_str(amount: &str) -> Result<Self, FzError> {
+        let invalid = || FzError::Input(format!("Invalid amount: {}", amount));
+
+        let (dollars, cents) = match amount.trim().split_once('.') {
+            Some((dollars, cents)) => (dollars, cents),
+            None => (amount.trim(), "0"),
+        };
+        //Only plain digits, so no sign can slip into either part
+        let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if !digits(dollars) || !digits(cents) || cents.len() > 2 {
+            return Err(invalid());
+        }
+        let dollars: i64 = dollars.parse().map_err(|_| invalid())?;
+        let cents: i64 = format!("{:0<2}", cents).parse().map_err(|_| invalid())?;
+        dollars
+            .checked_mul(100)
+            .and_then(|d| d.checked_add(cents))
+            .map(Amount)
+            .ok_or_else(invalid)
+    }
+}
+
+/// A purchase as the v1.0 purchases endpoint reports it
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct Purchase {
+    /// The gateway's id for the purchase, used to void, refund or capture it
+    pub id: String,
+    /// The merchant's reference
+    pub reference: String,
+    pub amount: Amount,
+    /// The ISO 4217 currency code, e.g. AUD
+    pub currency: String,
+    /// Whether the bank approved the purchase
+    #[serde(default = "yes")]
+    pub successful: bool,
+    pub message: String,
+    pub response_code: String,
+    /// The bank's authorization code
+    pub authorization: String,
+    pub card_holder: String,
+    /// The masked card number
+    pub card_number: String,
+    pub card_type: String,
+    #[serde(deserialize_with = "lenient_date")]
+    pub card_expiry: Option<NaiveDate>,
+    pub card_token: String,
+    #[serde(deserialize_with = "lenient_datetime")]
+    pub transaction_date: Option<DateTime<FixedOffset>>,
+    /// The day the funds settle, after which the purchase can only be refunded
+    #[serde(deserialize_with = "lenient_date")]
+    pub settlement_date: Option<NaiveDate>,
+    /// False for an authorization that is still waiting to be captured
+    #[serde(default = "yes")]
+    pub captured: bool,
+    pub captured_amount: Amount,
+    /// The total refunded so far
+    pub refunded_amount: Amount,
+    /// Set once the purchase has been voided
+    pub voided: bool,
+    /// Whatever the merchant attached to the purchase
+    pub metadata: HashMap<String, serde_json::Value>,
+}
+
+impl Purchase {
+    /// Whether the settlement date has been reached
+    pub fn is_settled(&self) -> bool {
+        matches!(self.settlement_date, Some(date) if date <= Utc::now().date_naive())
+    }
+
+    pub fn is_refunded(&self) -> bool {
+        self.refunded_amount.cents() > 0
+    }
+
+    /// What is left to refund after any earlier refunds
+    pub fn refundable(&self) -> Amount {
+        Amount::from_cents(self.amount.cents() - self.refunded_amount.cents())
+    }
+
+    /// Whether this is an authorization still holding funds on the card, which is released rather than voided
+    pub fn is_authorization(&self) -> bool {
+        self.successful && !self.captured && !self.voided
+    }
+
+    /// A one-word description of where the purchase is in its life
+    pub fn state(&self) -> &'static str {
+        if !self.successful {
+            "declined"
+        } else if self.voided {
+            "voided"
+        } else if self.is_refunded() && self.refunded_amount >= self.amount {
+            "refunded"
+        } else if self.is_refunded() {
+            "partially_refunded"
+        } else if !self.captured {
+            "authorized"
+        } else if self.is_settled() {
+            "settled"
+        } else {
+            "unsettled"
+        }
+    }
+}
+
+//Older replies leave these flags out, and then the purchase went through as normal
+fn yes() -> bool {
+    true
+}
+
+//Dates the gateway leaves out or formats unexpectedly shouldn't stop the whole purchase from being read
+fn lenient_date<'de, D>(deserializer: D) -> Result<Option<NaiveDate>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    let value = value.as_str().unwrap_or_default();
+    Ok(NaiveDate::parse_from_str(value.get(..10).unwrap_or(value), "%Y-%m-%d").ok())
+}
+
+fn lenient_datetime<'de, D>(deserializer: D) -> Result<Option<DateTime<FixedOffset>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(DateTime::parse_from_rfc3339(value.as_str().unwrap_or_default()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Amount;
+
+    fn cents(amount: &str) -> Option<i64> {
+        amount.parse::<Amount>().ok().map(Amount::cents)
+    }
+
+    #[test]
+    fn parses_dollars_and_cents() {
+        assert_eq!(cents("12"), Some(1200));
+        assert_eq!(cents("12.5"), Some(1250));
+        assert_eq!(cents(" 12.05 "), Some(1205));
+        assert_eq!(cents("0.50"), Some(50));
+    }
+
+    #[test]
+    fn rejects_signs_and_malformed_amounts() {
+        let invalid = [
+            "-0.50", "-1", "+1.00", "12.-5", "12.+5", "12.", ".50", "12.345", "1e3", "", "12.5.0",
+            "99999999999999999999",
+        ];
+        for amount in invalid {
+            assert_eq!(cents(amount), None, "{}", amount);
+        }
+    }
+
+    #[test]
+    fn displays_with_two_decimals() {
+        assert_eq!(Amount::from_cents(1205).to_string(), "12.05");
+        assert_eq!(Amount::from_cents(-50).to_string(), "-0.50");
+    }
+}
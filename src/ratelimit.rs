@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The longest any one wait is allowed to be, however slow the rate or late the Retry-After
+const MAX_WAIT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Default)]
+struct State {
+    tokens: f64,
+    refilled: Option<Instant>,
+    /// Set from a 429's Retry-After, holding back every request until then
+    paused_until: Option<Instant>,
+    /// How many requests are waiting now, and since when at least one has been
+    waiting: usize,
+    waiting_since: Option<Instant>,
+    /// Wall-clock time during which at least one request was held back, however many were
+    throttled: Duration,
+}
+
+/// A token bucket shared by every gateway request
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// Requests per second, or None for no limit
+    rate: Option<f64>,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<f64>) -> Self {
+        Self {
+            rate,
+            state: Mutex::new(State {
+                //Start full so a burst of up to one second's worth can go straight away
+                tokens: rate.unwrap_or_default().max(1.0),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Wait until a request may be sent
+    pub async fn acquire(&self) {
+        let mut waiting = None;
+        while let Some(wait) = self.reserve() {
+            waiting.get_or_insert_with(|| Waiting::start(self));
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take a token if one is free, otherwise say how long to wait before asking again
+    fn reserve(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+
+        if let Some(until) = state.paused_until {
+            if until > now {
+                return Some(until - now);
+            }
+            state.paused_until = None;
+        }
+
+        let rate = self.rate?;
+        let capacity = rate.max(1.0);
+        if let Some(refilled) = state.refilled {
+            state.tokens = (state.tokens + (now - refilled).as_secs_f64() * rate).min(capacity);
+        }
+        state.refilled = Some(now);
+
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            return None;
+        }
+        let wait = Duration::try_from_secs_f64((1.0 - state.tokens) / rate).unwrap_or(MAX_WAIT);
+        Some(wait.min(MAX_WAIT))
+    }
+
+    /// Hold back every request for `wait`, as asked by a Retry-After header
+    pub fn pause_for(&self, wait: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(until) = Instant::now().checked_add(wait.min(MAX_WAIT)) else {
+            return;
+        };
+        if state.paused_until.is_none_or(|current| until > current) {
+            state.paused_until = Some(until);
+        }
+    }
+
+    /// How long requests have been held back by the limiter, as wall-clock time
+    pub fn throttled(&self) -> Duration {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match state.waiting_since {
+            Some(since) => state.throttled + since.elapsed(),
+            None => state.throttled,
+        }
+    }
+}
+
+/// A request held back by the limiter, counted until it is let through or given up on
+struct Waiting<'a>(&'a RateLimiter);
+
+impl<'a> Waiting<'a> {
+    fn start(limiter: &'a RateLimiter) -> Self {
+        let mut state = limiter.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.waiting == 0 {
+            state.waiting_since = Some(Instant::now());
+        }
+        state.waiting += 1;
+        Self(limiter)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.waiting -= 1;
+        if state.waiting == 0 {
+            if let Some(since) = state.waiting_since.take() {
+                state.throttled += since.elapsed();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimiter, MAX_WAIT};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn waits_are_capped() {
+        let slow = RateLimiter::new(Some(1e-300));
+        assert_eq!(slow.reserve(), None);
+        assert_eq!(slow.reserve(), Some(MAX_WAIT));
+
+        let paused = RateLimiter::new(None);
+        paused.pause_for(Duration::MAX);
+        assert!(paused.reserve().is_some_and(|wait| wait <= MAX_WAIT));
+    }
+
+    #[tokio::test]
+    async fn throttled_time_is_counted_once_across_waiters() {
+        let limiter = RateLimiter::new(Some(20.0));
+        let started = Instant::now();
+        futures::future::join_all((0..40).map(|_| limiter.acquire())).await;
+
+        assert!(limiter.throttled() > Duration::ZERO);
+        assert!(limiter.throttled() <= started.elapsed());
+    }
+}
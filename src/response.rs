@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use serde::Deserialize;
+use serde::Deserializer;
+
+use crate::error::FzError;
+use crate::purchase::Purchase;
+
+/// Gateway error phrases meaning a purchase has settled and can only be refunded
+const VOID_WINDOW_CLOSED: [&str; 5] = [
+    "has settled",
+    "has been settled",
+    "already settled",
+    "cannot be voided",
+    "can no longer be voided",
+];
+/// Phrases that mean the purchase has not settled, whatever else the error says
+const NOT_SETTLED: [&str; 3] = ["unsettled", "not settled", "not yet settled"];
+
+/// The envelope the gateway wraps around every reply
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, bound(deserialize = "T: Deserialize<'de> + Default"))]
+pub struct FetchResponses<T = Purchase> {
+    /// The HTTP status of the reply
+    #[serde(skip)]
+    pub status: u16,
+    /// How many attempts the call took, counting retries
+    #[serde(skip)]
+    pub attempts: u32,
+    pub successful: bool,
+    #[serde(deserialize_with = "deserialize_optional_field")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Read with [`FetchResponses::response`] or [`FetchResponses::into_response`]
+    response: Option<Option<T>>,
+    #[serde(deserialize_with = "deserialize_optional_field")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Read with [`FetchResponses::errors`]
+    errors: Option<Option<FetchErrors>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(transparent)]
+pub struct FetchErrors {
+    pub errors: Vec<String>,
+}
+
+fn deserialize_optional_field<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    //Ok(Some(Option::deserialize(deserializer)?))
+    match Option::deserialize(deserializer) {
+        Ok(Some(r)) => Ok(r),
+        _ => Ok(None),
+    }
+}
+
+impl FetchResponses {
+    /// Whether the fetched purchase has been voided
+    pub fn is_voided(&self) -> bool {
+        matches!(self.response(), Some(r) if r.voided)
+    }
+
+    /// Whether a void reply means the purchase is now voided.
+    /// A retry refused as already voided means an earlier attempt landed.
+    pub fn void_landed(&self, refx: &str) -> bool {
+        self.successful || (self.attempts > 1 && matches!(self.error(refx), FzError::AlreadyVoided))
+    }
+
+    /// Whether a failed void was refused because the purchase is past the point of voiding.
+    /// This decides whether money is refunded instead, so anything unclear counts as no.
+    pub fn void_window_closed(&self) -> bool {
+        self.errors().iter().any(|e| {
+            let e = e.to_lowercase();
+            VOID_WINDOW_CLOSED.iter().any(|phrase| e.contains(phrase))
+                && !NOT_SETTLED.iter().any(|phrase| e.contains(phrase))
+        })
+    }
+}
+
+impl<T> FetchResponses<T> {
+    /// The purchase or purchases in the reply, if it had any
+    pub fn response(&self) -> Option<&T> {
+        self.response.as_ref().and_then(Option::as_ref)
+    }
+
+    /// Take the purchase or purchases out of the reply
+    pub fn into_response(self) -> Option<T> {
+        self.response.flatten()
+    }
+
+    /// The gateway's error list, empty when it sent none
+    pub fn errors(&self) -> &[String] {
+        match self.errors.as_ref().and_then(|e| e.as_ref()) {
+            Some(e) => &e.errors,
+            None => &[],
+        }
+    }
+
+    /// Classify an unsuccessful reply
+    pub fn error(&self, refx: &str) -> FzError {
+        let already_voided = self.errors().iter().any(|e| e.to_lowercase().contains("already voided"));
+        if self.status == 404 {
+            FzError::NotFound(refx.to_string())
+        } else if already_voided {
+            FzError::AlreadyVoided
+        } else {
+            FzError::GatewayDeclined(self.errors().to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FetchErrors, FetchResponses};
+
+    fn declined(error: &str) -> FetchResponses {
+        FetchResponses {
+            errors: Some(Some(FetchErrors {
+                errors: vec![error.to_string()],
+            })),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_settled_purchases_close_the_void_window() {
+        assert!(declined("Transaction has settled, please refund").void_window_closed());
+        assert!(declined("Transaction can no longer be voided").void_window_closed());
+        assert!(!declined("Batch is unsettled").void_window_closed());
+        assert!(!declined("Transaction cannot be voided: batch not yet settled").void_window_closed());
+        assert!(!declined("Settlement system unavailable").void_window_closed());
+        assert!(!declined("Invalid card").void_window_closed());
+    }
+}
@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::error::FzError;
+
+/// The delay before the first retry, doubled for each one after
+const BASE_DELAY: Duration = Duration::from_millis(500);
+/// No single wait is longer than this
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How long to wait after `attempt` failed, using full jitter so parallel workers spread out
+pub fn backoff(attempt: u32) -> Duration {
+    let ceiling = BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_DELAY);
+    ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+}
+
+/// Call `f` until it succeeds, fails with an error `retryable` rejects, or uses up `max_attempts`.
+/// Returns the result along with the number of attempts it took.
+pub async fn with_retries<T, F, Fut>(
+    max_attempts: u32,
+    retryable: fn(&FzError) -> bool,
+    mut f: F,
+) -> Result<(T, u32), FzError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, FzError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(e) if attempt < max_attempts && retryable(&e) => {
+                tokio::time::sleep(backoff(attempt)).await;
+                attempt += 1;
+            }
+            result => return result.map(|r| (r, attempt)),
+        }
+    }
+}
@@ -0,0 +1,728 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+//! End to end runs of the fzvoid binary against the mock gateway
+
+mod common;
+
+use common::{input_file, purchase, stderr, stdout, MockGateway, Reply};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+fn records(output: &std::process::Output) -> Vec<Value> {
+    stdout(output).lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+}
+
+#[test]
+fn voids_a_reference() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(purchase("ref1"))])
+        .on_void("071-P-ref1", vec![Reply::ok(json!({ "id": "071-P-ref1" }))]);
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1"]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "ref1 - Voided\n");
+    let auth = gateway.requests()[0].authorization.clone().unwrap();
+    assert_eq!(auth, "Basic bWVyY2hhbnQ6c2VjcmV0");
+}
+
+#[test]
+fn reports_each_row_of_a_file_in_order() {
+    let gateway = MockGateway::start();
+    for refx in ["a", "b", "c"] {
+        gateway
+            .on_fetch(refx, vec![Reply::ok(purchase(refx))])
+            .on_void(&format!("071-P-{}", refx), vec![Reply::ok(json!({}))]);
+    }
+    gateway.on_fetch("b", vec![Reply::ok(purchase("b")).delayed(Duration::from_millis(300))]);
+    let file = input_file("order", "a\nb\nmissing\nc\n");
+
+    let output = gateway.fzvoid(&["void", "-f", &file, "-c", "4", "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let results: Vec<_> = records(&output)
+        .iter()
+        .map(|r| format!("{}={}", r["reference"].as_str().unwrap(), r["result"].as_str().unwrap()))
+        .collect();
+    assert_eq!(results, ["a=voided", "b=voided", "missing=failed", "c=voided"]);
+    assert!(stderr(&output).contains("Voided: 3, Failed: 1, Skipped: 0"));
+}
+
+#[test]
+fn missing_purchase_is_not_found() {
+    let gateway = MockGateway::start();
+
+    let output = gateway.fzvoid(&["void", "-r", "nope", "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let record = &records(&output)[0];
+    assert_eq!(record["error_kind"], "not_found");
+    assert_eq!(record["http_status"], 404);
+}
+
+#[test]
+fn rejected_credentials_stop_the_run() {
+    let gateway = MockGateway::start();
+    gateway.on_fetch("ref1", vec![Reply::status(401)]);
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1", "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(records(&output)[0]["error_kind"], "auth_failed");
+}
+
+#[test]
+fn rejected_credentials_stop_a_file_at_the_first_refusal() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(purchase("ref1"))])
+        .on_void("071-P-ref1", vec![Reply::ok(json!({}))])
+        .on_fetch("ref2", vec![Reply::status(401)]);
+    for refx in ["ref3", "ref4", "ref5"] {
+        gateway.on_fetch(refx, vec![Reply::ok(purchase(refx))]);
+    }
+    let file = input_file("rejected", "ref1\nref2\nref3\nref4\nref5\n");
+
+    let output = gateway.fzvoid(&["void", "-f", &file, "-c", "1", "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    let records = records(&output);
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["result"], "voided");
+    assert_eq!(records[1]["error_kind"], "auth_failed");
+    assert!(gateway.requests_to("GET", "/v1.0/purchases/ref3").is_empty());
+    assert!(gateway.requests_to("GET", "/v1.0/purchases/ref5").is_empty());
+}
+
+#[test]
+fn gateway_errors_are_reported() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(purchase("ref1"))])
+        .on_void("071-P-ref1", vec![Reply::declined(&["Card issuer unavailable"])]);
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1", "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let record = &records(&output)[0];
+    assert_eq!(record["action"], "void");
+    assert_eq!(record["error_kind"], "gateway_declined");
+    assert_eq!(record["errors"], json!(["Card issuer unavailable"]));
+}
+
+#[test]
+fn outages_are_retried() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("ref1", vec![Reply::status(500), Reply::status(503), Reply::ok(purchase("ref1"))])
+        .on_void("071-P-ref1", vec![Reply::ok(json!({}))]);
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1", "--max-attempts", "3"]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", stdout(&output));
+    assert_eq!(gateway.requests_to("GET", "/v1.0/purchases/ref1").len(), 3);
+}
+
+#[test]
+fn outages_fail_once_attempts_run_out() {
+    let gateway = MockGateway::start();
+    gateway.on_fetch("ref1", vec![Reply::status(500)]);
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1", "--max-attempts", "2", "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(records(&output)[0]["error_kind"], "http_status");
+    assert_eq!(gateway.requests().len(), 2);
+}
+
+#[test]
+fn malformed_json_is_a_decode_error() {
+    let gateway = MockGateway::start();
+    gateway.on_fetch("ref1", vec![Reply::malformed()]);
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1", "-o", "jsonl"]);
+
+    assert_eq!(records(&output)[0]["error_kind"], "json_decode");
+}
+
+#[test]
+fn slow_replies_time_out() {
+    let gateway = MockGateway::start();
+    gateway.on_fetch("ref1", vec![Reply::ok(purchase("ref1")).delayed(Duration::from_secs(3))]);
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1", "--timeout", "1", "--max-attempts", "1", "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(records(&output)[0]["error_kind"], "transport");
+}
+
+#[test]
+fn dry_run_never_voids() {
+    let gateway = MockGateway::start();
+    gateway.on_fetch("ref1", vec![Reply::ok(purchase("ref1"))]);
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1", "--dry-run"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("ref1 - Would void 071-P-ref1 - 12.34 AUD - Jim Smith"));
+    assert_eq!(gateway.requests().len(), 1);
+}
+
+#[test]
+fn settled_purchases_fall_back_to_a_refund() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(purchase("ref1"))])
+        .on_void("071-P-ref1", vec![Reply::declined(&["Transaction has settled and cannot be voided"])])
+        .on_refund(vec![Reply::ok(json!({ "id": "071-R-1" }))]);
+    let file = input_file("fallback", "ref1,5.00\n");
+
+    let output = gateway.fzvoid(&["void", "--void-or-refund", "-f", &file]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", stdout(&output));
+    assert_eq!(stdout(&output), "ref1 - Refunded 5.00\n");
+    let refund = &gateway.requests_to("POST", "/v1.0/refunds")[0];
+    assert_eq!(
+        refund.json(),
+        json!({ "transaction_id": "071-P-ref1", "amount": 500, "reference": "ref1-refund-5.00" })
+    );
+}
+
+#[test]
+fn guards_skip_purchases_before_voiding() {
+    let gateway = MockGateway::start();
+    let mut usd = purchase("usd");
+    usd["currency"] = json!("USD");
+    gateway
+        .on_fetch("big", vec![Reply::ok(purchase("big"))])
+        .on_fetch("usd", vec![Reply::ok(usd)])
+        .on_fetch("old", vec![Reply::ok(purchase("old"))]);
+    let file = input_file("guards", "big\nusd\nold\n");
+
+    let max_amount = gateway.fzvoid(&["void", "-r", "big", "--max-amount", "10.00"]);
+    let currency = gateway.fzvoid(&["void", "-r", "usd", "--currency", "aud"]);
+    let age = gateway.fzvoid(&["void", "-f", &file, "--not-older-than", "24h", "-o", "jsonl"]);
+
+    assert_eq!(max_amount.status.code(), Some(0));
+    assert_eq!(stdout(&max_amount), "big - Skipped - Amount 12.34 is over --max-amount 10.00\n");
+    assert_eq!(stdout(&currency), "usd - Skipped - Currency USD is not AUD\n");
+    assert!(records(&age).iter().all(|r| r["result"] == "skipped"));
+    assert!(gateway.requests().iter().all(|r| r.method == "GET"));
+}
+
+#[test]
+fn ages_out_of_range_are_refused() {
+    let gateway = MockGateway::start();
+
+    for age in ["99999999999999999d", "-5h", "0m"] {
+        let output = gateway.fzvoid(&["void", "-r", "ref1", &format!("--not-older-than={}", age)]);
+        assert_eq!(output.status.code(), Some(2), "{}", age);
+        assert!(stderr(&output).contains("Invalid age"), "{}", age);
+    }
+    assert!(gateway.requests().is_empty());
+}
+
+#[test]
+fn production_needs_a_terminal_or_yes() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(purchase("ref1"))])
+        .on_void("071-P-ref1", vec![Reply::ok(json!({}))]);
+
+    let refused = gateway.fzvoid_in("production", &["void", "-r", "ref1"], b"y\n");
+    assert_eq!(refused.status.code(), Some(2));
+    assert!(stderr(&refused).contains("add --yes"));
+    assert!(gateway.requests().is_empty());
+
+    let dry_run = gateway.fzvoid_in("production", &["void", "-r", "ref1", "--dry-run"], b"");
+    assert_eq!(dry_run.status.code(), Some(0));
+
+    let confirmed = gateway.fzvoid_in("production", &["void", "-r", "ref1", "--yes"], b"");
+    assert_eq!(stdout(&confirmed), "ref1 - Voided\n");
+}
+
+#[test]
+fn reads_references_from_stdin() {
+    let gateway = MockGateway::start();
+    for refx in ["a", "c"] {
+        gateway
+            .on_fetch(refx, vec![Reply::ok(purchase(refx))])
+            .on_void(&format!("071-P-{}", refx), vec![Reply::ok(json!({}))]);
+    }
+
+    let output = gateway.fzvoid_with_stdin(&["void", "-f", "-", "-o", "jsonl"], b"a\r\n\xffb\nc");
+
+    assert_eq!(output.status.code(), Some(1));
+    let results: Vec<_> = records(&output).iter().map(|r| r["result"].clone()).collect();
+    assert_eq!(results, ["voided", "failed", "voided"]);
+    assert_eq!(records(&output)[1]["error"], "Line 2 is not valid UTF-8");
+    assert_eq!(gateway.requests().len(), 4);
+}
+
+#[test]
+fn refunds_stop_at_what_is_left() {
+    let gateway = MockGateway::start();
+    let mut part_refunded = purchase("ref1");
+    part_refunded["refunded_amount"] = json!(1000);
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(part_refunded)])
+        .on_refund(vec![Reply::ok(json!({}))]);
+
+    let over = gateway.fzvoid(&["refund", "-r", "ref1", "--amount", "5.00", "-o", "jsonl"]);
+    let zero = gateway.fzvoid(&["refund", "-r", "ref1", "--amount", "0", "--dry-run"]);
+    let rest = gateway.fzvoid(&["refund", "-r", "ref1", "-o", "jsonl"]);
+
+    assert_eq!(over.status.code(), Some(1));
+    assert_eq!(records(&over)[0]["error"], "Refund amount 5.00 is over the 2.34 left to refund");
+    assert_eq!(zero.status.code(), Some(2));
+    assert!(stderr(&zero).contains("Invalid amount"));
+    assert_eq!(records(&rest)[0]["result"], "refunded");
+    let refunds = gateway.requests_to("POST", "/v1.0/refunds");
+    assert_eq!(refunds.len(), 1);
+    assert_eq!(refunds[0].json()["amount"], 234);
+    assert_eq!(refunds[0].json()["reference"], "ref1-refund-2.34");
+}
+
+#[test]
+fn csv_input_maps_columns_and_carries_the_rest_through() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("a", vec![Reply::ok(purchase("a"))])
+        .on_refund(vec![Reply::ok(json!({}))]);
+    let file = input_file("mapped.csv", "Order,Ref,Refund,Reason\n17,a,2.50,\"damaged, returned\"\n");
+
+    let args = ["refund", "-f", &file, "--input-format", "csv", "--reference-column", "ref"];
+    let jsonl = gateway.fzvoid(&[&args[..], &["--amount-column", "3", "--note-column", "Reason", "-o", "jsonl"]].concat());
+    let csv = gateway.fzvoid(&[&args[..], &["--amount-column", "Refund", "-o", "csv"]].concat());
+
+    let record = &records(&jsonl)[0];
+    assert_eq!(record["result"], "refunded");
+    assert_eq!(record["note"], "damaged, returned");
+    assert_eq!(record["columns"], json!({ "Order": "17" }));
+    assert_eq!(gateway.requests_to("POST", "/v1.0/refunds")[0].json()["amount"], 250);
+    let lines: Vec<_> = stdout(&csv).lines().map(str::to_string).collect();
+    assert!(lines[0].ends_with(",elapsed_ms,note,Order,Reason"));
+    assert!(lines[1].ends_with(",,17,\"damaged, returned\""));
+}
+
+#[test]
+fn unreadable_rows_keep_their_columns() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("b", vec![Reply::ok(purchase("b"))])
+        .on_void("071-P-b", vec![Reply::ok(json!({}))]);
+    let input = b"Order,Ref\n\xff17,a\n18,b\n";
+
+    let args = ["void", "-f", "-", "--input-format", "csv", "--reference-column", "Ref"];
+    let csv = gateway.fzvoid_with_stdin(&[&args[..], &["-o", "csv"]].concat(), input);
+    let jsonl = gateway.fzvoid_with_stdin(&[&args[..], &["-o", "jsonl"]].concat(), input);
+
+    let lines: Vec<_> = stdout(&csv).lines().map(str::to_string).collect();
+    assert!(lines[0].ends_with(",note,Order"));
+    assert!(lines[1].starts_with("a,"));
+    assert!(lines[1].ends_with(",\u{fffd}17"));
+    let record = &records(&jsonl)[0];
+    assert_eq!(record["reference"], "a");
+    assert_eq!(record["error"], "Line 2 is not valid UTF-8");
+    assert_eq!(gateway.requests_to("GET", "/v1.0/purchases/a").len(), 0);
+}
+
+#[test]
+fn csv_input_needs_the_named_columns() {
+    let gateway = MockGateway::start();
+    let file = input_file("unmapped.csv", "reference,amount\na,1.00\n");
+
+    let output = gateway.fzvoid(&["refund", "-f", &file, "--input-format", "csv", "--note-column", "reason"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("No column named reason"));
+    assert!(gateway.requests().is_empty());
+}
+
+#[test]
+fn input_is_cleaned_and_deduplicated() {
+    let gateway = MockGateway::start();
+    for refx in ["a", "b"] {
+        gateway
+            .on_fetch(refx, vec![Reply::ok(purchase(refx))])
+            .on_void(&format!("071-P-{}", refx), vec![Reply::ok(json!({}))]);
+    }
+    let file = input_file("hygiene", "\u{feff}Reference\r\n  a  \r\n\r\n# done by hand\n   \nb\na\n");
+
+    let output = gateway.fzvoid(&["void", "-f", &file]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "a - Voided\nb - Voided\na - Skipped - Duplicate of line 2\n");
+    assert_eq!(gateway.requests().len(), 4);
+}
+
+#[test]
+fn gateway_ids_are_voided_without_a_lookup() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(purchase("ref1"))])
+        .on_void("071-P-ref1", vec![Reply::ok(json!({}))])
+        .on_void("071-P-ZVWY2XKF", vec![Reply::ok(json!({}))]);
+    let file = input_file("ids", "071-P-ZVWY2XKF\nref1\n");
+
+    let output = gateway.fzvoid(&["void", "-f", &file, "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let ids: Vec<_> = records(&output).iter().map(|r| r["purchase_id"].clone()).collect();
+    assert_eq!(ids, [json!("071-P-ZVWY2XKF"), json!("071-P-ref1")]);
+    let paths: Vec<_> = gateway.requests().iter().map(|r| r.path.clone()).collect();
+    assert_eq!(paths.iter().filter(|p| p.contains("ZVWY2XKF")).count(), 1);
+
+    //Anything that needs the purchase's details still looks it up, by id
+    gateway.on_fetch("071-P-ZVWY2XKF", vec![Reply::ok(purchase("ref9"))]);
+    let output = gateway.fzvoid(&["void", "--id", "071-P-ZVWY2XKF", "--dry-run"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("071-P-ZVWY2XKF - Would void"));
+}
+
+#[test]
+fn references_shaped_like_ids_fall_back_to_a_lookup() {
+    let gateway = MockGateway::start();
+    let mut order = purchase("123-AB-ORDER99");
+    order["id"] = json!("071-P-REAL01");
+    gateway
+        .on_fetch("123-AB-ORDER99", vec![Reply::ok(order)])
+        .on_void("071-P-REAL01", vec![Reply::ok(json!({}))]);
+
+    let output = gateway.fzvoid(&["void", "-r", "123-AB-ORDER99", "-o", "jsonl"]);
+    let by_id = gateway.fzvoid(&["void", "--id", "123-AB-ORDER99", "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(records(&output)[0]["purchase_id"], "071-P-REAL01");
+    assert_eq!(records(&output)[0]["result"], "voided");
+    //An id given as one is taken at its word
+    assert_eq!(records(&by_id)[0]["error_kind"], "not_found");
+    assert_eq!(gateway.requests_to("POST", "/v1.0/purchases/void?id=071-P-REAL01").len(), 1);
+}
+
+#[test]
+fn an_interrupted_run_resumes_from_its_journal() {
+    let gateway = MockGateway::start();
+    for refx in ["ref1", "ref2", "ref3"] {
+        gateway.on_void(&format!("071-P-{}", refx), vec![Reply::ok(json!({}))]);
+    }
+    let hung = Reply::ok(purchase("ref2")).delayed(Duration::from_secs(30));
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(purchase("ref1"))])
+        .on_fetch("ref2", vec![hung, Reply::ok(purchase("ref2"))])
+        .on_fetch("ref3", vec![Reply::ok(purchase("ref3"))]);
+    let file = input_file("resumed", "ref1\nref2\nref3\n");
+    let journal = input_file("resumed.jsonl", "");
+    //A refund of ref3 says nothing about whether it was voided
+    let refunded = json!({ "mode": "refund", "reference": "ref3", "purchase_id": null, "result": "refunded",
+        "timestamp": "2022-02-17T02:38:48Z", "error": null });
+    std::fs::write(&journal, format!("{}\n", refunded)).unwrap();
+
+    //Stop the first run while ref2 is still being fetched, after ref1 is journaled
+    let mut first = gateway.spawn(&["void", "-f", &file, "-c", "1", "--journal", &journal]);
+    let started = std::time::Instant::now();
+    while !std::fs::read_to_string(&journal).unwrap().contains("\"ref1\"") {
+        assert!(started.elapsed() < Duration::from_secs(10), "ref1 was never journaled");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    first.kill().unwrap();
+    first.wait().unwrap();
+
+    let output = gateway.fzvoid(&["void", "-f", &file, "--resume", &journal, "-o", "jsonl"]);
+    let missing = gateway.fzvoid(&["void", "-f", &file, "--resume", "/nonexistent/fzvoid/run.jsonl"]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let results: Vec<_> = records(&output).iter().map(|r| r["result"].clone()).collect();
+    assert_eq!(results, ["skipped", "voided", "voided"]);
+    assert_eq!(gateway.requests_to("POST", "/v1.0/purchases/void?id=071-P-ref1").len(), 1);
+    assert_eq!(missing.status.code(), Some(2));
+    assert!(stderr(&missing).contains("Error opening journal /nonexistent/fzvoid/run.jsonl"));
+}
+
+#[test]
+fn a_purchase_without_an_id_is_never_changed() {
+    let gateway = MockGateway::start();
+    gateway.on_fetch("ref1", vec![Reply::ok(json!({ "reference": "ref1" }))]);
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("Transaction not found: ref1"));
+    assert_eq!(gateway.requests().len(), 1);
+}
+
+#[test]
+fn every_gateway_call_is_audited_in_a_hash_chain() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(purchase("ref1"))])
+        .on_void("071-P-ref1", vec![Reply::status(500), Reply::ok(json!({ "id": "071-P-ref1" }))]);
+    let log = input_file("audit", "");
+
+    let output = gateway.fzvoid(&["void", "-r", "ref1", "--audit-log", &log]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let output = gateway.fzvoid(&["fetch", "-r", "ref1", "--audit-log", &log]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+
+    let contents = std::fs::read_to_string(&log).unwrap();
+    assert!(!contents.contains("secret"));
+    let entries: Vec<Value> = contents.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    let calls: Vec<_> = entries
+        .iter()
+        .map(|e| format!("{} {}", e["action"].as_str().unwrap(), e["http_status"]))
+        .collect();
+    assert_eq!(calls, ["fetch 200", "void 500", "fetch 200", "void 200", "fetch 200"]);
+    assert_eq!(entries[3]["merchant"], "merchant");
+    assert_eq!(entries[3]["reference"], "ref1");
+    assert_eq!(entries[3]["purchase_id"], "071-P-ref1");
+    assert_eq!(entries[3]["response"]["successful"], true);
+    assert_eq!(entries[4]["prev_hash"], entries[3]["hash"]);
+
+    let output = gateway.fzvoid(&["verify-audit", &log]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stderr(&output).contains("intact: 5 entries"));
+
+    std::fs::write(&log, contents.replacen("\"void\"", "\"fetch\"", 1)).unwrap();
+    let output = gateway.fzvoid(&["verify-audit", &log]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("broken at line 2"));
+}
+
+#[test]
+fn runs_sharing_an_audit_log_keep_one_chain() {
+    let gateway = MockGateway::start();
+    let refs: Vec<String> = (0..10).map(|i| format!("shared{}", i)).collect();
+    for refx in &refs {
+        gateway
+            .on_fetch(refx, vec![Reply::ok(purchase(refx))])
+            .on_void(&format!("071-P-{}", refx), vec![Reply::ok(json!({}))]);
+    }
+    let file = input_file("shared", &refs.join("\n"));
+    let log = input_file("shared-audit", "");
+
+    let args = ["void", "-f", &file, "-c", "4", "--audit-log", &log];
+    let runs: Vec<_> = (0..2).map(|_| gateway.spawn(&args)).collect();
+    for run in runs {
+        assert_eq!(run.wait_with_output().unwrap().status.code(), Some(0));
+    }
+
+    let output = gateway.fzvoid(&["verify-audit", &log]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stderr(&output).contains("intact: 40 entries"));
+}
+
+#[test]
+fn a_call_missing_from_the_audit_log_fails_its_row() {
+    let gateway = MockGateway::start();
+    gateway
+        .on_fetch("ref1", vec![Reply::ok(purchase("ref1")).delayed(Duration::from_millis(300))])
+        .on_void("071-P-ref1", vec![Reply::ok(json!({}))]);
+    let log = input_file("unwritable-audit", "");
+
+    //Spoil the log while the fetch is out, so its entry can't be chained on
+    let run = gateway.spawn(&["void", "-r", "ref1", "--audit-log", &log, "-o", "jsonl"]);
+    let started = std::time::Instant::now();
+    while gateway.requests().is_empty() {
+        assert!(started.elapsed() < Duration::from_secs(10), "ref1 was never fetched");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    std::fs::write(&log, "{not an entry\n").unwrap();
+    let output = run.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let record = &records(&output)[0];
+    assert_eq!(record["result"], "failed");
+    assert!(record["error"].as_str().unwrap().contains("could not be written to the audit log"));
+    assert!(gateway.requests_to("POST", "/v1.0/purchases/void?id=071-P-ref1").is_empty());
+}
+
+#[test]
+fn verify_checks_that_voids_landed() {
+    let gateway = MockGateway::start();
+    let mut voided = purchase("a");
+    voided["voided"] = json!(true);
+    gateway
+        .on_fetch("a", vec![Reply::ok(purchase("a")), Reply::ok(voided)])
+        .on_void("071-P-a", vec![Reply::ok(json!({}))])
+        .on_fetch("b", vec![Reply::ok(purchase("b"))])
+        .on_void("071-P-b", vec![Reply::ok(json!({}))]);
+    let file = input_file("verify", "a\nb\n");
+
+    let output = gateway.fzvoid(&["void", "-f", &file, "--verify"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stdout(&output),
+        "a - Voided\nb - Unverified - The void was accepted but the purchase is not voided\n"
+    );
+    assert!(stderr(&output).contains("Voided: 1, Unverified: 1, Failed: 0"));
+    assert_eq!(gateway.requests().len(), 6);
+}
+
+#[test]
+fn status_looks_up_each_reference_without_changing_it() {
+    let gateway = MockGateway::start();
+    let mut voided = purchase("a");
+    voided["voided"] = json!(true);
+    gateway.on_fetch("a", vec![Reply::ok(voided)]).on_fetch("b", vec![Reply::ok(purchase("b"))]);
+
+    let output = gateway.fzvoid_with_stdin(&["status", "--stdin"], b"a\nmissing\nb\n");
+
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let lines: Vec<Vec<String>> = stdout(&output)
+        .lines()
+        .map(|l| l.split_whitespace().map(str::to_string).collect())
+        .collect();
+    assert_eq!(lines[0], ["Reference", "Exists", "State", "Voided", "Refunded", "Settled", "Amount"]);
+    assert_eq!(lines[1][..4], ["a", "yes", "voided", "yes"]);
+    assert_eq!(lines[2], ["missing", "no"]);
+    assert_eq!(lines[3][..4], ["b", "yes", "settled", "no"]);
+    assert!(stderr(&output).contains("Found: 2, Not found: 1, Failed: 0"));
+    assert!(gateway.requests().iter().all(|r| r.method == "GET"));
+
+    let output = gateway.fzvoid(&["status", "-r", "missing", "-o", "jsonl"]);
+    let record = &records(&output)[0];
+    assert_eq!(record["result"], "not_found");
+    assert_eq!(record["exists"], false);
+    assert_eq!(record["state"], Value::Null);
+}
+
+#[test]
+fn authorizations_are_released_instead_of_voided() {
+    let gateway = MockGateway::start();
+    let mut auth = purchase("auth");
+    auth["captured"] = json!(false);
+    gateway
+        .on_fetch("auth", vec![Reply::ok(auth.clone())])
+        .on_release("071-P-auth", vec![Reply::ok(json!({}))]);
+
+    let output = gateway.fzvoid(&["void", "-r", "auth", "--dry-run"]);
+    assert!(stdout(&output).starts_with("auth - Would release 071-P-auth"));
+
+    let output = gateway.fzvoid(&["void", "-r", "auth"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "auth - Released\n");
+    assert!(gateway.requests_to("POST", "/v1.0/purchases/void?id=071-P-auth").is_empty());
+
+    //Given by id there is no lookup, so a refused void is what shows it is an authorization
+    auth["id"] = json!("071-P-AUTH0001");
+    gateway
+        .on_void("071-P-AUTH0001", vec![Reply::declined(&["Transaction cannot be voided"])])
+        .on_fetch("071-P-AUTH0001", vec![Reply::ok(auth)])
+        .on_release("071-P-AUTH0001", vec![Reply::ok(json!({}))]);
+    let output = gateway.fzvoid(&["void", "--id", "071-P-AUTH0001", "-o", "jsonl"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let record = &records(&output)[0];
+    assert_eq!(record["result"], "released");
+    assert_eq!(record["action"], "release");
+}
+
+#[test]
+fn captures_authorizations_for_each_rows_amount() {
+    let gateway = MockGateway::start();
+    for refx in ["a", "c"] {
+        let mut auth = purchase(refx);
+        auth["captured"] = json!(false);
+        gateway.on_fetch(refx, vec![Reply::ok(auth)]);
+    }
+    gateway
+        .on_fetch("b", vec![Reply::ok(purchase("b"))])
+        .on_capture("071-P-a", vec![Reply::ok(json!({}))]);
+    let file = input_file("capture", "a,5.00\nb,5.00\nc,20.00\n");
+
+    let output = gateway.fzvoid(&["capture", "-f", &file]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stdout(&output),
+        "a - Captured 5.00\n\
+         b - Skipped - Nothing to capture: the purchase is settled\n\
+         c - Capture failed - Capture amount 20.00 is over the authorized 12.34\n"
+    );
+    assert_eq!(gateway.requests_to("POST", "/v1.0/purchases/071-P-a/capture")[0].json()["amount"], 500);
+    assert!(stderr(&output).contains("Captured: 1, Failed: 1, Skipped: 1"));
+
+    //A gateway id is looked up too, so the same checks apply before anything is captured
+    gateway.on_fetch("071-P-SETTLED01", vec![Reply::ok(purchase("b"))]);
+    let output = gateway.fzvoid(&["capture", "--id", "071-P-SETTLED01", "--amount", "999.00"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "071-P-SETTLED01 - Skipped - Nothing to capture: the purchase is settled\n");
+    let ids = input_file("capture-ids", "071-P-AUTH0002,999.00\n");
+    let mut auth = purchase("d");
+    auth["captured"] = json!(false);
+    gateway.on_fetch("071-P-AUTH0002", vec![Reply::ok(auth)]);
+    let output = gateway.fzvoid(&["capture", "-f", &ids, "--key", "id"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("Capture amount 999.00 is over the authorized 12.34"));
+    assert!(gateway.requests().iter().all(|r| !r.path.ends_with("/capture") || r.path.contains("071-P-a")));
+}
+
+#[test]
+fn quoted_csv_fields_can_span_lines() {
+    let gateway = MockGateway::start();
+    for refx in ["r1", "r2"] {
+        gateway
+            .on_fetch(refx, vec![Reply::ok(purchase(refx))])
+            .on_void(&format!("071-P-{}", refx), vec![Reply::ok(json!({}))]);
+    }
+    let file = input_file(
+        "multiline",
+        "reference,note\nr1,\"line one\nline two\"\nr2,\"say \"\"hi\"\"\"\nr3,\"never closed\nr4\n",
+    );
+
+    let output = gateway.fzvoid(&["void", "-f", &file, "--input-format", "csv", "-o", "jsonl"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let results = records(&output);
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0]["note"], "line one\nline two");
+    assert_eq!(results[1]["note"], "say \"hi\"");
+    assert_eq!(results[2]["error"], "Line 5 has a quoted field that is never closed");
+    assert_eq!(results[2]["error_kind"], "input");
+    //Only the two complete rows were looked up, never a fragment of one
+    assert_eq!(gateway.requests().len(), 4);
+}
+
+#[test]
+fn references_are_encoded_and_must_come_back_unchanged() {
+    let gateway = MockGateway::start();
+    let mut odd = purchase("order1#retry");
+    odd["id"] = json!("071-P-odd");
+    gateway
+        .on_fetch("order1%23retry", vec![Reply::ok(odd)])
+        .on_void("071-P-odd", vec![Reply::ok(json!({}))])
+        .on_fetch("order2", vec![Reply::ok(purchase("order1"))]);
+
+    let output = gateway.fzvoid(&["void", "-r", "order1#retry"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "order1#retry - Voided\n");
+
+    //A purchase with some other reference is never changed
+    let output = gateway.fzvoid(&["void", "-r", "order2", "-o", "jsonl"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(records(&output)[0]["error_kind"], "wrong_purchase");
+    assert!(gateway.requests_to("POST", "/v1.0/purchases/void?id=071-P-order1").is_empty());
+}
+
+#[test]
+fn concurrency_caps_requests_across_fetches_and_changes() {
+    let gateway = MockGateway::start();
+    let refs: Vec<String> = (0..8).map(|i| format!("r{}", i)).collect();
+    //Uneven delays keep fetches going while earlier references are being voided
+    for (i, refx) in refs.iter().enumerate() {
+        let fetch = Duration::from_millis(if i % 3 == 0 { 400 } else { 50 });
+        gateway
+            .on_fetch(refx, vec![Reply::ok(purchase(refx)).delayed(fetch)])
+            .on_void(&format!("071-P-{}", refx), vec![Reply::ok(json!({})).delayed(Duration::from_millis(300))]);
+    }
+    let file = input_file("concurrency", &refs.join("\n"));
+
+    let output = gateway.fzvoid(&["void", "-f", &file, "-c", "4"]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(gateway.requests().len(), 16);
+    assert!(gateway.most_in_flight() <= 4, "{} requests at once", gateway.most_in_flight());
+}
@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+//! The library client against the mock gateway
+
+mod common;
+
+use common::{purchase, MockGateway, Reply};
+use fzvoid::{Amount, FatZebraClient, FzError, SearchQuery};
+use serde_json::json;
+use std::time::Duration;
+
+fn client(gateway: &MockGateway) -> FatZebraClient {
+    FatZebraClient::new("merchant", "secret", gateway.url())
+}
+
+#[tokio::test]
+async fn fetches_a_typed_purchase() {
+    let gateway = MockGateway::start();
+    gateway.on_fetch("ref1", vec![Reply::ok(purchase("ref1"))]);
+
+    let fe = client(&gateway).fetch_purchase("ref1").await.unwrap();
+
+    let p = fe.into_response().unwrap();
+    assert_eq!(p.id, "071-P-ref1");
+    assert_eq!(p.amount, Amount::from_cents(1234));
+    assert_eq!(p.card_holder, "Jim Smith");
+    assert_eq!(p.settlement_date.unwrap().to_string(), "2022-02-18");
+    assert_eq!(p.state(), "settled");
+}
+
+#[tokio::test]
+async fn a_lost_void_reply_is_checked_before_retrying() {
+    let gateway = MockGateway::start();
+    let mut voided = purchase("ref1");
+    voided["voided"] = json!(true);
+    gateway
+        .on_void("071-P-ref1", vec![Reply::ok(json!({})).delayed(Duration::from_secs(3))])
+        .on_fetch("ref1", vec![Reply::ok(voided)]);
+
+    let client = client(&gateway).with_timeout(Duration::from_secs(1));
+    let reply = client.void_purchase("ref1", "071-P-ref1").await.unwrap();
+
+    assert!(reply.void_landed("ref1"));
+    assert_eq!(gateway.requests_to("POST", "/v1.0/purchases/void?id=071-P-ref1").len(), 1);
+}
+
+#[tokio::test]
+async fn refunds_are_not_repeated_after_an_outage() {
+    let gateway = MockGateway::start();
+    gateway.on_refund(vec![Reply::status(502), Reply::ok(json!({}))]);
+
+    let err = client(&gateway).refund("ref1", "071-P-ref1", Amount::from_cents(500)).await.unwrap_err();
+
+    assert!(matches!(err, FzError::HttpStatus(502)));
+    assert_eq!(gateway.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn searches_a_date_range() {
+    let gateway = MockGateway::start();
+    gateway.on(
+        "GET",
+        "/v1.0/purchases?from=2022-01-01&limit=100&offset=0",
+        vec![Reply::ok(json!([purchase("a"), purchase("b")]))],
+    );
+
+    let query = SearchQuery { from: "2022-01-01".to_string(), to: None, limit: 100, offset: 0 };
+    let fe = client(&gateway).search_purchases(&query).await.unwrap();
+
+    let references: Vec<_> = fe.into_response().unwrap().into_iter().map(|p| p.reference).collect();
+    assert_eq!(references, ["a", "b"]);
+}
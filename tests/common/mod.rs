@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022 Robert Mascaro
+
+//! A scriptable stand-in for the Fat Zebra gateway, so the client and the whole
+//! command line can be tested on a machine with no network.
+
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// One scripted reply
+#[derive(Clone, Debug)]
+pub struct Reply {
+    status: u16,
+    body: String,
+    delay: Duration,
+    headers: Vec<(String, String)>,
+}
+
+impl Reply {
+    /// A successful reply wrapping `response` in the gateway's envelope
+    pub fn ok(response: Value) -> Self {
+        Self::json(200, json!({ "successful": true, "response": response, "errors": [] }))
+    }
+
+    /// A reply the gateway processed and refused, with its error list
+    pub fn declined(errors: &[&str]) -> Self {
+        Self::json(200, json!({ "successful": false, "response": null, "errors": errors }))
+    }
+
+    pub fn not_found() -> Self {
+        Self::json(404, json!({ "successful": false, "response": null, "errors": ["Record not found"] }))
+    }
+
+    pub fn json(status: u16, body: Value) -> Self {
+        Self::raw(status, &body.to_string())
+    }
+
+    /// A bare status, such as a 401 or 500, with an empty body
+    pub fn status(status: u16) -> Self {
+        Self::raw(status, "")
+    }
+
+    /// A 200 whose body isn't JSON
+    pub fn malformed() -> Self {
+        Self::raw(200, "<html>Bad gateway</html>")
+    }
+
+    pub fn raw(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            body: body.to_string(),
+            delay: Duration::ZERO,
+            headers: Vec::new(),
+        }
+    }
+
+    /// Wait this long before replying
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// A request the gateway received
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+    pub authorization: Option<String>,
+}
+
+impl Request {
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap_or(Value::Null)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Replies for each "METHOD path", used in order with the last one repeating
+    routes: HashMap<String, Vec<Reply>>,
+    requests: Vec<Request>,
+    /// Requests received but not yet answered, and the most there have been at once
+    in_flight: usize,
+    most_in_flight: usize,
+}
+
+pub struct MockGateway {
+    url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockGateway {
+    /// Listen on a free local port until the test process exits
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind mock gateway");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let shared = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = shared.clone();
+                thread::spawn(move || serve(stream, &state));
+            }
+        });
+        Self { url, state }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The most requests the gateway was ever handling at once
+    pub fn most_in_flight(&self) -> usize {
+        self.state.lock().unwrap().most_in_flight
+    }
+
+    /// Script the replies to `method path`, e.g. `GET /v1.0/purchases/ref1`
+    pub fn on(&self, method: &str, path: &str, replies: Vec<Reply>) -> &Self {
+        let key = format!("{} {}", method, path);
+        self.state.lock().unwrap().routes.insert(key, replies);
+        self
+    }
+
+    /// Script the replies to a fetch of `reference`
+    pub fn on_fetch(&self, reference: &str, replies: Vec<Reply>) -> &Self {
+        self.on("GET", &format!("/v1.0/purchases/{}", reference), replies)
+    }
+
+    /// Script the replies to a void of the purchase with gateway id `id`
+    pub fn on_void(&self, id: &str, replies: Vec<Reply>) -> &Self {
+        self.on("POST", &format!("/v1.0/purchases/void?id={}", id), replies)
+    }
+
+    /// Script the replies to releasing the authorization with gateway id `id`
+    pub fn on_release(&self, id: &str, replies: Vec<Reply>) -> &Self {
+        self.on("POST", &format!("/v1.0/purchases/{}/release", id), replies)
+    }
+
+    /// Script the replies to capturing the authorization with gateway id `id`
+    pub fn on_capture(&self, id: &str, replies: Vec<Reply>) -> &Self {
+        self.on("POST", &format!("/v1.0/purchases/{}/capture", id), replies)
+    }
+
+    pub fn on_refund(&self, replies: Vec<Reply>) -> &Self {
+        self.on("POST", "/v1.0/refunds", replies)
+    }
+
+    /// Every request received so far
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The requests received for `method path`
+    pub fn requests_to(&self, method: &str, path: &str) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.method == method && r.path == path)
+            .collect()
+    }
+
+    /// Run the fzvoid binary against this gateway with throwaway credentials
+    pub fn fzvoid(&self, args: &[&str]) -> Output {
+        self.fzvoid_with_stdin(args, b"")
+    }
+
+    pub fn fzvoid_with_stdin(&self, args: &[&str], stdin: &[u8]) -> Output {
+        self.fzvoid_in("custom", args, stdin)
+    }
+
+    /// Run the fzvoid binary as if `environment` were served from this gateway
+    pub fn fzvoid_in(&self, environment: &str, args: &[&str], stdin: &[u8]) -> Output {
+        let mut child = self.spawn_in(environment, args);
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        child.wait_with_output().expect("wait for fzvoid")
+    }
+
+    /// Start the fzvoid binary and leave it running, so a test can interrupt it
+    pub fn spawn(&self, args: &[&str]) -> Child {
+        self.spawn_in("custom", args)
+    }
+
+    fn spawn_in(&self, environment: &str, args: &[&str]) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_fzvoid"))
+            .args(["-u", "merchant", "-t", "secret", "-e", environment, "--base-url", &self.url])
+            .args(args)
+            .env("FZ_CONFIG", "/nonexistent/fzvoid/config.toml")
+            .env_remove("FZ_USERNAME")
+            .env_remove("FZ_TOKEN")
+            .env_remove("FZ_PROFILE")
+            .env_remove("FZ_AUDIT_LOG")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("run fzvoid")
+    }
+}
+
+/// A purchase as the gateway would return it for `reference`, with id `071-P-<reference>`
+pub fn purchase(reference: &str) -> Value {
+    json!({
+        "id": format!("071-P-{}", reference),
+        "reference": reference,
+        "amount": 1234,
+        "currency": "AUD",
+        "successful": true,
+        "message": "Approved",
+        "authorization": "55355",
+        "card_holder": "Jim Smith",
+        "card_number": "512345XXXXXX2346",
+        "card_type": "MasterCard",
+        "transaction_date": "2022-02-17T13:38:48+11:00",
+        "settlement_date": "2022-02-18",
+        "captured": true,
+        "captured_amount": 1234,
+        "metadata": {},
+    })
+}
+
+/// Write `contents` to a file named after the test, so parallel tests don't collide
+pub fn input_file(name: &str, contents: &str) -> String {
+    let path = std::env::temp_dir().join(format!("fzvoid-{}-{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn serve(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut length = 0;
+    let mut authorization = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).unwrap_or(0) == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            match name.trim().to_lowercase().as_str() {
+                "content-length" => length = value.trim().parse().unwrap_or(0),
+                "authorization" => authorization = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    let _ = reader.read_exact(&mut body);
+
+    let reply = {
+        let mut state = state.lock().unwrap();
+        state.in_flight += 1;
+        state.most_in_flight = state.most_in_flight.max(state.in_flight);
+        state.requests.push(Request {
+            method: method.clone(),
+            path: path.clone(),
+            body: String::from_utf8_lossy(&body).into_owned(),
+            authorization,
+        });
+        match state.routes.get_mut(&format!("{} {}", method, path)) {
+            Some(replies) if replies.len() > 1 => replies.remove(0),
+            Some(replies) if !replies.is_empty() => replies[0].clone(),
+            _ => Reply::not_found(),
+        }
+    };
+
+    thread::sleep(reply.delay);
+    let mut stream = reader.into_inner();
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        reply.status,
+        reply.body.len()
+    );
+    for (name, value) in &reply.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(reply.body.as_bytes());
+    state.lock().unwrap().in_flight -= 1;
+}